@@ -1,29 +1,96 @@
-use clap::Parser;
+#[cfg(feature = "arrow")]
+mod arrow;
+mod crc32;
+mod merge;
+mod messages;
+mod playback;
+mod scrub;
+mod sha256;
+mod source;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+#[cfg(feature = "upload")]
+mod upload;
+mod validate;
+mod zip;
+
+use clap::parser::ValueSource;
+use clap::{ArgAction, ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use crc32::Crc32;
+use crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
 use crossterm::style::Print;
 use crossterm::terminal::ClearType;
 use crossterm::{cursor, execute, terminal};
 use figlet_rs::FIGfont;
+use messages::Messages;
 use rand::prelude::*;
 use serial2::SerialPort;
-use std::fs::File;
-use std::io::{self, BufWriter, Stdout, Write};
+use source::{PipeSource, Source, TcpSource};
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, IsTerminal, Read, Stdout, Write};
 use std::path::Path;
-use std::sync::LazyLock;
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock, PoisonError};
 use std::thread;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::{fs, io::BufRead, path::PathBuf};
 
-const WARMUP_LINE_COUNT: usize = 500; // how many serial lines to discard as warm-up
+const WARMUP_LINE_COUNT: usize = 500; // default number of serial lines to discard as warm-up
 const FLUSH_EVERY: usize = 5_000; // flush readings every N lines
+const FLUSH_INTERVAL_MS: u128 = 1_000; // ...or at least this often while samples arrive
+const FLUSH_EVERY_MIN: usize = 100; // bounds for the line count chosen by --flush-target-ms
+const FLUSH_EVERY_MAX: usize = 50_000;
+const CLIP_WARN_WINDOW_MS: u128 = 1_000; // share of clipped readings checked this often
 const DEFAULT_DEVICE_NAME: &str = "/dev/serial/by-id/usb-1a86_USB_Serial-if00-port0";
 const DEFAULT_DIR: &str = ".";
+const INDEX_FILE_NAME: &str = "index.csv";
+const LABELS_HEADER: &str = "timestamp;activity;extra"; // only with --label-names
+const END_MARKER: &str = "end"; // `#end` annotation closing labels.csv of a clean session
+const INDEX_HEADER: &str = "dir;timestamp;sex;hand;height;samples;operator;study";
+const LOCK_FILE_NAME: &str = ".fidgetsense.lock";
+const DEFAULT_SALT_FILE_NAME: &str = ".fidgetsense.salt";
+const HASHED_ID_DIR_CHARS: usize = 16; // hex digits of the hashed id used in dir names
 const BAUD: u32 = 115200;
+const READ_TIMEOUT: Duration = Duration::from_millis(100);
+const CONTACT_CHECK_DURATION: Duration = Duration::from_secs(1);
+const DEVICE_PROBE_DURATION: Duration = Duration::from_secs(1);
+const RECONNECT_TIMEOUT: Duration = Duration::from_secs(10); // for the device to come back
+const RECONNECT_DELAY: Duration = Duration::from_millis(500); // between tries to reopen it
+const BAUD_PROBE_DURATION: Duration = Duration::from_millis(300); // per rate, with --auto-baud
+const DEVICE_BANNER_LINES: usize = 5;
+const INVALID_LINES_REPORTED: usize = 5; // invalid UTF-8 lines echoed to stderr
+const FIRST_LINE_MAX_DROPPED: usize = 10; // --first-line validate records anyway after this many
+const EMBEDDED_SUBJECT_COLUMNS: usize = 3; // sex, hand and height, with --embed-subject
+const COMMON_BAUD_RATES: [u32; 8] = [9600, 19200, 38400, 57600, 115200, 230400, 460800, 921600];
 
-static FIGFONT: LazyLock<FIGfont> =
-    LazyLock::new(|| FIGfont::standard().expect("Failed to load FIGfont"));
+static FIGFONT: OnceLock<FIGfont> = OnceLock::new();
+static MESSAGES: OnceLock<Messages> = OnceLock::new();
+static INSTRUCTIONS: OnceLock<Messages> = OnceLock::new();
+/// --safe-mode: print plain lines, never move the cursor or clear the screen.
+static SAFE_MODE: AtomicBool = AtomicBool::new(false);
+/// `--dev -`: stdin carries the readings, so nothing may be prompted for.
+static STDIN_SOURCE: AtomicBool = AtomicBool::new(false);
+const FIGLET_FONT_DIRS: [&str; 2] = ["/usr/share/figlet", "/usr/local/share/figlet"];
 const COUNTDOWN_DURATION_SEC: Duration = Duration::from_secs(1);
 const COUNTDOWN_FROM: u32 = 5;
+const BEEP_GAP: Duration = Duration::from_millis(250); // between the bells of a --beeps pattern
+const STATUS_INTERVAL: Duration = Duration::from_secs(1); // between status line redraws
+const RATE_WINDOW_MS: u128 = 2000; // readings the status line's live rate is taken over
+const BASELINE_TEXT: &str = "Sit still with your eyes closed until the screen changes.";
 const ACTIVITY_DURATION_SEC: Duration = Duration::from_secs(15);
+const REHEARSAL_TIME_SCALE: f64 = 0.05; // --rehearse-countdown runs at 20x speed
+const CALIBRATION_INSTRUCTIONS: &str =
+    "Lay the sensor flat and keep it still, then slowly rotate it a full turn.";
+
+const PHRASES: [&str; 5] = [
+    "the quick brown fox jumps over the lazy dog",
+    "a stitch in time saves nine",
+    "pack my box with five dozen liquor jugs",
+    "every cloud has a silver lining",
+    "practice makes perfect",
+];
 
 const TEXTS: [&str; 5] = [
     "The tortoise and the hare are often seen as representing two different approaches to life. The hare is fast and confident, often rushing ahead, while the tortoise is slow and steady, never losing focus. In the end, the tortoise won the race because it was consistent and patient.",
@@ -33,15 +100,40 @@ const TEXTS: [&str; 5] = [
     "Music has the power to transport us to another time and place. It can evoke memories, stir emotions, and bring people together. From classical compositions to modern pop songs, music is a universal language that transcends borders and connects us to something greater than ourselves.",
 ];
 
+#[allow(clippy::upper_case_acronyms, non_camel_case_types)]
 #[derive(Clone, Debug)]
 enum Activity {
     NOTHING,
     TYPING,
+    /// Typing a short phrase exactly, scored against it.
+    TYPING_EXACT,
     SCROLLING,
     FIDGETING,
+    CALIBRATION,
     OTHER,
+    /// The countdown before an activity, with --prepare-label.
+    PREPARE,
+    /// The --baseline segment at the start of the session.
+    BASELINE,
+    /// The --cooldown after each activity.
+    COOLDOWN,
 }
 
+const ALL_ACTIVITIES: [Activity; 10] = [
+    Activity::NOTHING,
+    Activity::TYPING,
+    Activity::TYPING_EXACT,
+    Activity::SCROLLING,
+    Activity::FIDGETING,
+    Activity::OTHER,
+    Activity::CALIBRATION,
+    Activity::PREPARE,
+    Activity::BASELINE,
+    Activity::COOLDOWN,
+];
+
+const HANDS: [&str; 2] = ["left", "right"];
+
 const ACTIVITIES_ARR: [Activity; 8] = [
     Activity::NOTHING,
     Activity::NOTHING,
@@ -53,48 +145,1115 @@ const ACTIVITIES_ARR: [Activity; 8] = [
     Activity::FIDGETING,
 ];
 
+const EXIT_CODES_HELP: &str = "Exit codes:
+  0  success
+  1  I/O error (reading, writing or creating files)
+  2  the device could not be opened
+  3  validation failure (bad arguments, directory or recording)
+  4  aborted by the user
+  5  disk full
+  6  aborted by the participant (--abort-key)";
+
+/// Category of a failed run, reported as the process exit code (see `EXIT_CODES_HELP`).
+#[derive(Clone, Copy, Debug)]
+enum Failure {
+    Io = 1,
+    Device = 2,
+    Validation = 3,
+    UserAbort = 4,
+    DiskFull = 5,
+    ParticipantAbort = 6,
+}
+
+#[derive(Debug)]
+struct SessionError {
+    failure: Failure,
+    error: io::Error,
+}
+
+impl SessionError {
+    fn new(failure: Failure, error: io::Error) -> Self {
+        SessionError { failure, error }
+    }
+}
+
+impl From<io::Error> for SessionError {
+    fn from(error: io::Error) -> Self {
+        let failure = match error.kind() {
+            io::ErrorKind::StorageFull | io::ErrorKind::QuotaExceeded => Failure::DiskFull,
+            io::ErrorKind::Interrupted => Failure::UserAbort,
+            io::ErrorKind::InvalidInput | io::ErrorKind::InvalidData => Failure::Validation,
+            _ => Failure::Io,
+        };
+        SessionError::new(failure, error)
+    }
+}
+
+/// How the activities are arranged before the session.
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum OrderPolicy {
+    /// Shuffle all activities
+    Full,
+    /// Keep the reps of each activity together, shuffling the order of the groups
+    ByType,
+    /// Keep the built-in order
+    None,
+}
+
+/// What happens to a reading whose timestamp is behind the previous one's, after the
+/// system clock was set back.
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum BackwardsTimestamps {
+    /// Record it with the previous timestamp
+    Clamp,
+    /// Record it as is and mark it in labels.csv with a `#clock_backwards` row
+    Flag,
+}
+
+/// What happens to the first line after the warm-up, which may be the tail of a
+/// frame the warm-up cut in half.
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum FirstLine {
+    /// Record it like any other line
+    Keep,
+    /// Drop exactly one line
+    Skip,
+    /// Drop lines until one parses as a complete reading
+    Validate,
+}
+
+impl FirstLine {
+    /// Whether `line` is the first to record, after `dropped` lines were dropped.
+    fn accepts(self, line: &str, dropped: usize, expected_columns: Option<usize>) -> bool {
+        match self {
+            FirstLine::Keep => true,
+            FirstLine::Skip => dropped >= 1,
+            FirstLine::Validate => {
+                dropped >= FIRST_LINE_MAX_DROPPED || is_valid_reading(line, expected_columns)
+            }
+        }
+    }
+}
+
+/// Parity bit of each character on the serial line.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum Parity {
+    None,
+    Odd,
+    Even,
+}
+
+impl Parity {
+    fn to_serial(self) -> serial2::Parity {
+        match self {
+            Parity::None => serial2::Parity::None,
+            Parity::Odd => serial2::Parity::Odd,
+            Parity::Even => serial2::Parity::Even,
+        }
+    }
+
+    /// The letter in the usual `8N1` shorthand.
+    fn letter(self) -> char {
+        match self {
+            Parity::None => 'N',
+            Parity::Odd => 'O',
+            Parity::Even => 'E',
+        }
+    }
+}
+
+/// Flow control on the serial line.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum FlowControl {
+    None,
+    /// XON/XOFF characters in the stream
+    Software,
+    /// The RTS/CTS lines
+    Hardware,
+}
+
+impl FlowControl {
+    fn to_serial(self) -> serial2::FlowControl {
+        match self {
+            FlowControl::None => serial2::FlowControl::None,
+            FlowControl::Software => serial2::FlowControl::XonXoff,
+            FlowControl::Hardware => serial2::FlowControl::RtsCts,
+        }
+    }
+}
+
+/// File format of the recorded readings.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+enum OutputFormat {
+    /// `timestamp;line` rows in readings.csv
+    Csv,
+    /// One JSON object per reading in readings.ndjson: `{"t":..,"fields":[..]}`, or
+    /// `{"t":..,"raw":".."}` for lines that are not numeric
+    Ndjson,
+    /// Arrow IPC file readings.arrow: an int64 timestamp column and a float64 column
+    /// per field, written in record batches at every flush
+    #[cfg(feature = "arrow")]
+    Arrow,
+}
+
+impl OutputFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Csv => "csv",
+            OutputFormat::Ndjson => "ndjson",
+            #[cfg(feature = "arrow")]
+            OutputFormat::Arrow => "arrow",
+        }
+    }
+}
+
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, after_help = EXIT_CODES_HELP)]
 struct Args {
     /// Path to the recordings directory
-    #[arg(long)]
+    #[arg(long, env = "FIDGETSENSE_DIR")]
     dir: Option<PathBuf>,
 
-    /// Path to the devive
-    #[arg(long)]
+    /// Subdirectory for new recordings under --dir, with %Y, %m, %d, %H, %M and %S
+    /// replaced by the local date and time, e.g. "%Y-%m-%d"
+    #[arg(long, env = "FIDGETSENSE_DIR_TEMPLATE", value_parser = parse_dir_template)]
+    dir_template: Option<String>,
+
+    /// Path to the device or to a FIFO another process writes readings into, host:port
+    /// to read from a TCP socket, or - to read from standard input (every prompt must
+    /// then be answered by an option)
+    #[arg(long, env = "FIDGETSENSE_DEV")]
     dev: Option<PathBuf>,
+
+    /// Baud rate of the device
+    #[arg(long, env = "FIDGETSENSE_BAUD", default_value_t = BAUD)]
+    baud: u32,
+
+    /// Try each common baud rate before recording and use the one whose lines are
+    /// most often numeric, instead of --baud
+    #[arg(long, env = "FIDGETSENSE_AUTO_BAUD", conflicts_with = "baud")]
+    auto_baud: bool,
+
+    /// Parity of the serial line
+    #[arg(long, env = "FIDGETSENSE_PARITY", value_enum, default_value_t = Parity::None)]
+    parity: Parity,
+
+    /// Stop bits per character on the serial line (1 or 2)
+    #[arg(
+        long,
+        env = "FIDGETSENSE_STOP_BITS",
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u8).range(1..=2)
+    )]
+    stop_bits: u8,
+
+    /// Flow control on the serial line
+    #[arg(long, env = "FIDGETSENSE_FLOW_CONTROL", value_enum, default_value_t = FlowControl::None)]
+    flow_control: FlowControl,
+
+    /// Number of serial lines to discard as warm-up [default: 500]
+    #[arg(long, env = "FIDGETSENSE_WARMUP_LINES", conflicts_with = "warmup_secs")]
+    warmup_lines: Option<usize>,
+
+    /// Seconds of serial data to discard as warm-up, instead of a line count
+    #[arg(long, env = "FIDGETSENSE_WARMUP_SECS", value_parser = parse_secs)]
+    warmup_secs: Option<Duration>,
+
+    /// End the warm-up once this many valid lines arrive in a row, instead of a fixed
+    /// line count or time
+    #[arg(
+        long,
+        env = "FIDGETSENSE_WARMUP_STABLE_LINES",
+        conflicts_with_all = ["warmup_lines", "warmup_secs"]
+    )]
+    warmup_stable_lines: Option<usize>,
+
+    /// Give up waiting for --warmup-stable-lines after this many seconds and record anyway
+    #[arg(
+        long,
+        env = "FIDGETSENSE_WARMUP_TIMEOUT_SECS",
+        value_parser = parse_secs,
+        default_value = "30",
+        requires = "warmup_stable_lines"
+    )]
+    warmup_timeout_secs: Duration,
+
+    /// Check that reading timestamps never go backwards (the wall clock may be set back
+    /// mid-session) and clamp or flag the ones that do
+    #[arg(long, env = "FIDGETSENSE_BACKWARDS_TIMESTAMPS", value_enum)]
+    backwards_timestamps: Option<BackwardsTimestamps>,
+
+    /// What to do with the first line after the warm-up, which may be a partial frame
+    #[arg(long, env = "FIDGETSENSE_FIRST_LINE", value_enum, default_value_t = FirstLine::Validate)]
+    first_line: FirstLine,
+
+    /// Abort if fewer than this fraction of warm-up lines are numeric (0 disables)
+    #[arg(long, env = "FIDGETSENSE_MIN_VALID_FRACTION", default_value_t = 0.2)]
+    min_valid_fraction: f64,
+
+    /// Only count warm-up lines with exactly this many fields as valid
+    #[arg(long, env = "FIDGETSENSE_EXPECTED_COLUMNS")]
+    expected_columns: Option<usize>,
+
+    /// Environment tag stored in chars.txt, e.g. --env input=trackpad (repeatable)
+    #[arg(long = "env", value_name = "KEY=VALUE", value_parser = parse_env_tag)]
+    env_tags: Vec<(String, String)>,
+
+    /// FIGlet font for the banners: a .flf path or a font name from the system figlet dir
+    #[arg(long, env = "FIDGETSENSE_FONT")]
+    font: Option<String>,
+
+    /// Participant identifier, recorded in chars.txt and appended to the recording dir name
+    #[arg(long, env = "FIDGETSENSE_PARTICIPANT_ID", value_parser = parse_identifier)]
+    participant_id: Option<String>,
+
+    /// Operator running the session, recorded in chars.txt and the index
+    #[arg(long, env = "FIDGETSENSE_OPERATOR", value_parser = parse_identifier)]
+    operator: Option<String>,
+
+    /// Study or experiment the session belongs to, recorded in chars.txt and the index
+    #[arg(long, env = "FIDGETSENSE_STUDY", value_parser = parse_identifier)]
+    study: Option<String>,
+
+    /// Put recordings in a subdirectory of --dir named after the --study
+    #[arg(long, env = "FIDGETSENSE_STUDY_DIR", requires = "study")]
+    study_dir: bool,
+
+    /// File listing the allowed operator ids, one per line. Without --operator, the
+    /// operator is asked for.
+    #[arg(long, env = "FIDGETSENSE_OPERATORS_FILE")]
+    operators_file: Option<PathBuf>,
+
+    /// Store hex(SHA-256(salt + participant id)) instead of the raw participant id. The
+    /// dir name gets the first 16 hex digits. The salt never enters the recording.
+    #[arg(long, env = "FIDGETSENSE_HASH_ID", requires = "participant_id")]
+    hash_id: bool,
+
+    /// File holding the salt for --hash-id, created with a random salt if missing
+    /// [default: ~/.fidgetsense.salt]
+    #[arg(long, env = "FIDGETSENSE_SALT_FILE")]
+    salt_file: Option<PathBuf>,
+
+    /// Skip the subject questions and mark the session as anonymous
+    #[arg(long, env = "FIDGETSENSE_NO_SUBJECT")]
+    no_subject: bool,
+
+    /// Append the subject to every row of readings.csv as three constant columns after
+    /// the device's: `timestamp;<fields>;sex;hand;height`, height empty if not given
+    #[arg(long, env = "FIDGETSENSE_EMBED_SUBJECT", conflicts_with = "no_subject")]
+    embed_subject: bool,
+
+    /// Where the sensor is worn, instead of asking. A value not in --placements is
+    /// recorded as an "other" placement with this description
+    #[arg(long, env = "FIDGETSENSE_PLACEMENT")]
+    placement: Option<String>,
+
+    /// Comma-separated sensor placements to choose from; "other" is always offered too
+    #[arg(
+        long,
+        env = "FIDGETSENSE_PLACEMENTS",
+        value_delimiter = ',',
+        value_parser = parse_identifier,
+        default_value = "wrist,finger,desk"
+    )]
+    placements: Vec<String>,
+
+    /// Record again with the options of the last session in --dir, offering to keep
+    /// its subject; options given now replace the recorded ones
+    #[arg(long)]
+    repeat_last: bool,
+
+    /// No terminal bell or status notices
+    #[arg(long, env = "FIDGETSENSE_QUIET")]
+    quiet: bool,
+
+    /// Ring a distinct number of terminal bells as each activity starts: 1 for n, 2 for
+    /// t, 3 for s, 4 for f, 5 for e, 6 for c and 7 for o
+    #[arg(long, env = "FIDGETSENSE_BEEPS", conflicts_with = "quiet")]
+    beeps: bool,
+
+    /// Comma-separated bell patterns replacing the --beeps defaults, as code=count or
+    /// code=count@gap_ms (e.g. t=2,f=3@400)
+    #[arg(
+        long,
+        env = "FIDGETSENSE_BEEP_PATTERNS",
+        value_delimiter = ',',
+        value_parser = parse_beep_pattern,
+        requires = "beeps"
+    )]
+    beep_patterns: Vec<BeepPattern>,
+
+    /// Index of the contact-quality field in each serial line, checked before recording
+    #[arg(long, env = "FIDGETSENSE_QUALITY_FIELD", requires = "quality_min")]
+    quality_field: Option<usize>,
+
+    /// Minimum average contact quality required to start recording
+    #[arg(long, env = "FIDGETSENSE_QUALITY_MIN", requires = "quality_field")]
+    quality_min: Option<f64>,
+
+    /// Seed for the activity order and texts (random if omitted)
+    #[arg(long, env = "FIDGETSENSE_SEED")]
+    seed: Option<u64>,
+
+    /// Tell the participant which hand to use, chosen at random per activity
+    #[arg(long, env = "FIDGETSENSE_RANDOMIZE_HAND")]
+    randomize_hand: bool,
+
+    /// Draw the typing texts without replacement, so no text comes up again before all
+    /// of them were used (nor twice in a row when they are reshuffled)
+    #[arg(long, env = "FIDGETSENSE_NO_REPEAT_TEXTS")]
+    no_repeat_texts: bool,
+
+    /// Before each 'o' activity, ask the operator to type what the participant should do;
+    /// it is shown as the instruction and written after the label
+    #[arg(long, env = "FIDGETSENSE_DESCRIBE_OTHER")]
+    describe_other: bool,
+
+    /// Comma-separated activity codes to run instead of the default set (e.g. t,s,f,n)
+    #[arg(
+        long,
+        env = "FIDGETSENSE_ACTIVITIES",
+        value_delimiter = ',',
+        value_parser = parse_activity_code
+    )]
+    activities: Vec<Activity>,
+
+    /// Comma-separated activities to leave out of this session, by code or name
+    /// (e.g. typing,scrolling)
+    #[arg(
+        long,
+        env = "FIDGETSENSE_EXCLUDE",
+        value_delimiter = ',',
+        value_parser = parse_activity_code
+    )]
+    exclude: Vec<Activity>,
+
+    /// Refuse to start unless the session has at least this many activities
+    #[arg(long, env = "FIDGETSENSE_MIN_ACTIVITIES", default_value_t = 1)]
+    min_activities: usize,
+
+    /// How to order the activities
+    #[arg(long, env = "FIDGETSENSE_ORDER", value_enum, default_value_t = OrderPolicy::Full)]
+    order: OrderPolicy,
+
+    /// Arrange the activities by hand at a prompt before the session starts
+    #[arg(long, env = "FIDGETSENSE_INTERACTIVE_ORDER", conflicts_with = "order")]
+    interactive_order: bool,
+
+    /// Run a calibration activity before the shuffled activities
+    #[arg(long, env = "FIDGETSENSE_CALIBRATE")]
+    calibrate: bool,
+
+    /// Flag gaps between consecutive readings longer than this many milliseconds
+    #[arg(long, env = "FIDGETSENSE_MAX_GAP_MS")]
+    max_gap_ms: Option<u128>,
+
+    /// Nominal sample rate of the device in Hz, for --fill-gaps
+    #[arg(long, env = "FIDGETSENSE_EXPECTED_HZ", value_parser = parse_positive)]
+    expected_hz: Option<f64>,
+
+    /// Where readings are missing, insert NaN rows at the timestamps --expected-hz
+    /// predicts, so the rows keep a uniform rate
+    #[arg(
+        long,
+        env = "FIDGETSENSE_FILL_GAPS",
+        requires = "expected_hz",
+        conflicts_with = "average"
+    )]
+    fill_gaps: bool,
+
+    /// Full-scale range of the device as MIN:MAX. Values at or beyond a bound are
+    /// counted as clipped, per channel, and reported in the summary
+    #[arg(
+        long,
+        env = "FIDGETSENSE_CLIP_RANGE",
+        value_parser = parse_clip_range,
+        allow_hyphen_values = true
+    )]
+    clip_range: Option<(f64, f64)>,
+
+    /// Warn while more than this percentage of the readings in a second are clipped
+    #[arg(
+        long,
+        env = "FIDGETSENSE_CLIP_WARN_PERCENT",
+        value_parser = parse_positive,
+        requires = "clip_range"
+    )]
+    clip_warn_percent: Option<f64>,
+
+    /// Also write detected gaps to labels.csv as `#gap` annotation rows
+    #[arg(long, env = "FIDGETSENSE_GAP_LABELS", requires = "max_gap_ms")]
+    gap_labels: bool,
+
+    /// Abort if no data arrives for this many milliseconds during an activity
+    #[arg(long, env = "FIDGETSENSE_WATCHDOG_MS")]
+    watchdog_ms: Option<u128>,
+
+    /// Abort after this many consecutive empty lines during an activity
+    #[arg(long, env = "FIDGETSENSE_MAX_EMPTY_LINES", value_parser = clap::value_parser!(u64).range(1..))]
+    max_empty_lines: Option<u64>,
+
+    /// Reopen the device, reconnect to the TCP address or wait for the FIFO's next
+    /// writer, and warm up again, when the source goes away mid-session; at most this
+    /// many times before giving up
+    #[arg(long, env = "FIDGETSENSE_RECONNECT")]
+    reconnect: Option<usize>,
+
+    /// Also write the end-of-session summary as JSON to this file
+    #[arg(long, env = "FIDGETSENSE_SUMMARY_JSON")]
+    summary_json: Option<PathBuf>,
+
+    /// Also write the label timeline to labels.json as `{start, end, label, text}`
+    /// intervals, for timeline viewers
+    #[arg(long, env = "FIDGETSENSE_LABELS_JSON")]
+    labels_json: bool,
+
+    /// Write integer class ids instead of letters in labels.csv (mapping in chars.txt)
+    #[arg(long, env = "FIDGETSENSE_NUMERIC_LABELS")]
+    numeric_labels: bool,
+
+    /// Write activity names ("typing", "scrolling", ...) instead of letters in
+    /// labels.csv, under a header row
+    #[arg(
+        long,
+        env = "FIDGETSENSE_LABEL_NAMES",
+        conflicts_with = "numeric_labels"
+    )]
+    label_names: bool,
+
+    /// Write reading, label and event timestamps as ms since the session started
+    /// (the start's epoch time goes to chars.txt)
+    #[arg(long, env = "FIDGETSENSE_RELATIVE_TIME")]
+    relative_time: bool,
+
+    /// Format of the readings file
+    #[arg(long, env = "FIDGETSENSE_FORMAT", value_enum, default_value_t = OutputFormat::Csv)]
+    format: OutputFormat,
+
+    /// Write the mean of every N consecutive readings instead of each reading
+    #[arg(long, env = "FIDGETSENSE_AVERAGE", value_parser = clap::value_parser!(u32).range(1..))]
+    average: Option<u32>,
+
+    /// Rewrite each reading with ';' between the fields, whatever the device sends.
+    /// Tokens that are not numbers become NaN, and rows are padded with NaN or cut to
+    /// --expected-columns (or the first row's width)
+    #[arg(long, env = "FIDGETSENSE_NORMALIZE")]
+    normalize: bool,
+
+    /// With --normalize, write every number with this many decimals
+    #[arg(long, env = "FIDGETSENSE_DECIMALS", requires = "normalize")]
+    decimals: Option<usize>,
+
+    /// Write each label window to its own readings_<label>_<window>.csv instead of readings.csv
+    #[arg(long, env = "FIDGETSENSE_SPLIT_BY_ACTIVITY")]
+    split_by_activity: bool,
+
+    /// Also write every labels.csv row into readings.csv, in timestamp order, as
+    /// `<timestamp>;#label;<label>` (annotations as `<timestamp>;#<kind>;...`)
+    #[arg(
+        long,
+        env = "FIDGETSENSE_INLINE_LABELS",
+        conflicts_with = "split_by_activity"
+    )]
+    inline_labels: bool,
+
+    /// Start a new readings.<n>.csv once the current one would grow past this many bytes
+    #[arg(
+        long,
+        env = "FIDGETSENSE_MAX_FILE_BYTES",
+        conflicts_with = "split_by_activity",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    max_file_bytes: Option<u64>,
+
+    /// Seconds to show a "get ready" screen before the first activity
+    #[arg(long, env = "FIDGETSENSE_START_DELAY", default_value_t = 0)]
+    start_delay: u64,
+
+    /// Seconds of rest to keep recording after the last activity
+    #[arg(long, env = "FIDGETSENSE_TAIL_SECONDS", default_value_t = 0)]
+    tail_seconds: u64,
+
+    /// Every N samples, write a '#crc32=...' line covering the rows since the previous one
+    #[arg(long, env = "FIDGETSENSE_CHECKSUM_EVERY", value_parser = clap::value_parser!(u64).range(1..))]
+    checksum_every: Option<u64>,
+
+    /// End each activity after this many readings instead of after a fixed time
+    #[arg(long, env = "FIDGETSENSE_SAMPLES_PER_ACTIVITY", value_parser = clap::value_parser!(u64).range(1..))]
+    samples_per_activity: Option<u64>,
+
+    /// End each activity when the participant presses Enter instead of after a fixed time
+    #[arg(
+        long,
+        env = "FIDGETSENSE_SELF_PACED",
+        conflicts_with_all = ["samples_per_activity", "duration_jitter_ms"]
+    )]
+    self_paced: bool,
+
+    /// Let the participant end the session early by pressing x during an activity
+    /// (not while typing). The recording is marked as aborted by the participant.
+    #[arg(long, env = "FIDGETSENSE_ABORT_KEY")]
+    abort_key: bool,
+
+    /// Let the operator mark sub-segments by pressing b during an activity (not while
+    /// typing), written to labels.csv as numbered `#boundary` rows
+    #[arg(long, env = "FIDGETSENSE_BOUNDARY_KEY")]
+    boundary_key: bool,
+
+    /// After the session, pack the recording directory into <dir>.zip next to it
+    #[arg(long, env = "FIDGETSENSE_ZIP")]
+    zip: bool,
+
+    /// Remove the recording directory once the zip is written
+    #[arg(long, env = "FIDGETSENSE_ZIP_REMOVE", requires = "zip")]
+    zip_remove: bool,
+
+    /// Seconds of baseline recording, with its own instruction, before the first activity
+    #[arg(long, env = "FIDGETSENSE_BASELINE_SECS")]
+    baseline_secs: Option<u64>,
+
+    /// Instruction shown during the baseline
+    #[arg(long, env = "FIDGETSENSE_BASELINE_TEXT", requires = "baseline_secs")]
+    baseline_text: Option<String>,
+
+    /// Label code written for the baseline
+    #[arg(
+        long,
+        env = "FIDGETSENSE_BASELINE_LABEL",
+        default_value = "b",
+        value_parser = parse_label_code
+    )]
+    baseline_label: Activity,
+
+    /// Seconds of cooldown, with its own label, after each activity
+    #[arg(long, env = "FIDGETSENSE_COOLDOWN_SECS")]
+    cooldown_secs: Option<u64>,
+
+    /// Label code written for the cooldown
+    #[arg(
+        long,
+        env = "FIDGETSENSE_COOLDOWN_LABEL",
+        default_value = "d",
+        value_parser = parse_label_code
+    )]
+    cooldown_label: Activity,
+
+    /// Label the countdown before each activity 'p' instead of 'o'
+    #[arg(long, env = "FIDGETSENSE_PREPARE_LABEL")]
+    prepare_label: bool,
+
+    /// File of per-activity instruction variants to draw from, e.g.
+    /// fidgeting = ["Tap your foot!", "Click a pen!"]
+    #[arg(long, env = "FIDGETSENSE_INSTRUCTIONS")]
+    instructions: Option<PathBuf>,
+
+    /// Messages file translating the participant-facing texts
+    #[arg(long, env = "FIDGETSENSE_LANG")]
+    lang: Option<PathBuf>,
+
+    /// Show the device path and what it sends, and ask before recording from it
+    #[arg(long, env = "FIDGETSENSE_CONFIRM_DEVICE")]
+    confirm_device: bool,
+
+    /// Print the raw and parsed form of the first N recorded lines to stderr
+    #[arg(long, env = "FIDGETSENSE_DEBUG_FIRST", default_value_t = 0)]
+    debug_first: usize,
+
+    /// Vary each activity's duration by up to this many milliseconds either way
+    #[arg(
+        long,
+        env = "FIDGETSENSE_DURATION_JITTER_MS",
+        conflicts_with = "samples_per_activity"
+    )]
+    duration_jitter_ms: Option<u64>,
+
+    /// Vary each countdown tick by up to this many milliseconds either way, so activity
+    /// onsets aren't perfectly periodic
+    #[arg(long, env = "FIDGETSENSE_COUNTDOWN_JITTER_MS")]
+    countdown_jitter_ms: Option<u64>,
+
+    /// Write a '#heartbeat;<timestamp>' line to the readings every this many seconds,
+    /// whether or not data arrives
+    #[arg(long, env = "FIDGETSENSE_HEARTBEAT_SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    heartbeat_secs: Option<u64>,
+
+    /// Stop recording after this many seconds from the start of the session, even if
+    /// the protocol hasn't finished
+    #[arg(long, env = "FIDGETSENSE_MAX_SESSION_SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    max_session_secs: Option<u64>,
+
+    /// Sync readings to disk (fsync) on every flush, at least once a second, so a power
+    /// loss costs at most that much data. Slows recording on slow storage
+    #[arg(long, env = "FIDGETSENSE_FSYNC")]
+    fsync: bool,
+
+    /// Adapt how many lines go between flushes so each flush takes at most about this
+    /// many milliseconds (readings are still flushed at least once a second)
+    #[arg(long, env = "FIDGETSENSE_FLUSH_TARGET_MS", value_parser = clap::value_parser!(u64).range(1..))]
+    flush_target_ms: Option<u64>,
+
+    /// After the session, POST the recording's zip to this http:// URL
+    #[cfg(feature = "upload")]
+    #[arg(long, env = "FIDGETSENSE_UPLOAD", conflicts_with = "zip_remove")]
+    upload: Option<String>,
+
+    /// Let --upload send the recording over plain, unencrypted HTTP, the only kind it
+    /// supports
+    #[cfg(feature = "upload")]
+    #[arg(long, env = "FIDGETSENSE_ALLOW_INSECURE_UPLOAD", requires = "upload")]
+    allow_insecure_upload: bool,
+
+    /// Also insert readings, labels and the session's metadata into this SQLite
+    /// database; runs the sqlite3 command-line shell, which must be installed and on
+    /// PATH
+    #[cfg(feature = "sqlite")]
+    #[arg(long, env = "FIDGETSENSE_SQLITE")]
+    sqlite: Option<PathBuf>,
+
+    /// Also write events.csv with microsecond stimulus-onset times
+    #[arg(long, env = "FIDGETSENSE_EVENTS")]
+    events: bool,
+
+    /// Append '<epoch ms>;<phase>;<activity>' to this file at every protocol transition,
+    /// e.g. to line a screen recording up with the session
+    #[arg(long, env = "FIDGETSENSE_TRANSITION_LOG")]
+    transition_log: Option<PathBuf>,
+
+    /// Never touch the terminal (no clearing, cursor moves or FIGlet banners), just
+    /// print plain lines
+    #[arg(long, env = "FIDGETSENSE_SAFE_MODE")]
+    safe_mode: bool,
+
+    /// Walk through the activities without opening the device or writing any files,
+    /// then offer to start the real recording
+    #[arg(long, env = "FIDGETSENSE_PRACTICE")]
+    practice: bool,
+
+    /// Flash through every screen of the protocol (countdowns, activities, texts) at
+    /// high speed to check how it renders, then exit without opening the device or
+    /// writing any files
+    #[arg(
+        long,
+        env = "FIDGETSENSE_REHEARSE_COUNTDOWN",
+        conflicts_with = "practice"
+    )]
+    rehearse_countdown: bool,
+
+    /// Log readings until Ctrl+C without the activity protocol: no countdowns or
+    /// activities, and labels.csv holds at most the --raw-log-label
+    #[arg(long, env = "FIDGETSENSE_RAW_LOG", conflicts_with_all = ["practice", "self_paced"])]
+    raw_log: bool,
+
+    /// Label code for the whole --raw-log recording; none by default
+    #[arg(
+        long,
+        env = "FIDGETSENSE_RAW_LOG_LABEL",
+        value_parser = parse_label_code,
+        requires = "raw_log"
+    )]
+    raw_log_label: Option<Activity>,
+
+    /// Print the effective settings and where each came from, then exit
+    #[arg(long)]
+    print_config: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Check that an existing recording directory is well-formed
+    Validate {
+        /// Path to the recording directory
+        dir: PathBuf,
+    },
+    /// Combine recordings into one CSV with session id and subject columns
+    Merge {
+        /// Recording directories, or base directories to take every recording from
+        #[arg(required = true)]
+        dirs: Vec<PathBuf>,
+
+        /// Where to write the combined CSV
+        #[arg(long, short)]
+        out: PathBuf,
+
+        /// Pad rows with empty fields instead of failing when column counts differ
+        #[arg(long)]
+        pad: bool,
+    },
+    /// Replay a recording in the terminal, one bar per channel
+    Playback {
+        /// Path to the recording directory
+        dir: PathBuf,
+
+        /// Playback speed relative to the original timing
+        #[arg(long, default_value_t = 1.0, value_parser = parse_positive)]
+        speed: f64,
+
+        /// Milliseconds of readings summarized on each line
+        #[arg(long, default_value_t = 100, value_parser = clap::value_parser!(u64).range(1..))]
+        interval_ms: u64,
+    },
+    /// Copy a recording with direct identifiers removed from or hashed in chars.txt
+    Scrub {
+        /// Path to the recording directory
+        dir: PathBuf,
+
+        /// Where to write the sanitized copy
+        #[arg(long, short)]
+        out: PathBuf,
+
+        /// chars.txt keys to leave out; a trailing `*` matches any rest, as in `env.*`
+        #[arg(
+            long,
+            value_delimiter = ',',
+            default_value = "operator,placement_other"
+        )]
+        drop: Vec<String>,
+
+        /// chars.txt keys whose values are replaced by hex(SHA-256(salt + value))
+        #[arg(long, value_delimiter = ',', default_value = "participant_id")]
+        hash: Vec<String>,
+
+        /// File holding the salt, shared with --hash-id [default: ~/.fidgetsense.salt]
+        #[arg(long, env = "FIDGETSENSE_SALT_FILE")]
+        salt_file: Option<PathBuf>,
+    },
+}
+
+fn main() -> ExitCode {
+    let matches = Args::command().get_matches();
+    let args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    if args.print_config {
+        print_config(&matches);
+        return ExitCode::SUCCESS;
+    }
+
+    match run(args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {}", e.error);
+            ExitCode::from(e.failure as u8)
+        }
+    }
+}
+
+/// Prints every option as TOML, with its source as a trailing comment.
+fn print_config(matches: &ArgMatches) {
+    let command = Args::command();
+    for arg in command.get_arguments() {
+        let id = arg.get_id().as_str();
+        if matches!(id, "help" | "version" | "print_config") {
+            continue;
+        }
+
+        let values: Vec<String> = matches
+            .get_raw(id)
+            .into_iter()
+            .flatten()
+            .map(|value| value.to_string_lossy().into_owned())
+            .collect();
+        let source = match matches.value_source(id) {
+            Some(ValueSource::CommandLine) => "command line".to_string(),
+            Some(ValueSource::EnvVariable) => format!(
+                "env {}",
+                arg.get_env().unwrap_or_default().to_string_lossy()
+            ),
+            Some(ValueSource::DefaultValue) => "default".to_string(),
+            _ => {
+                match built_in_default(id) {
+                    Some(value) => println!("{} = {} # built-in default", id, value),
+                    None => println!("# {} is unset", id),
+                }
+                continue;
+            }
+        };
+
+        let is_flag = matches!(arg.get_action(), ArgAction::SetTrue | ArgAction::SetFalse);
+        let value = match values.as_slice() {
+            [value] if is_flag => value.clone(),
+            [value] if arg.get_num_args().is_none_or(|n| n.max_values() <= 1) => toml_string(value),
+            values => format!(
+                "[{}]",
+                values
+                    .iter()
+                    .map(|value| toml_string(value))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        };
+        println!("{} = {} # {}", id, value, source);
+    }
 }
 
-fn main() -> std::io::Result<()> {
-    let args = Args::parse();
+/// Defaults applied in code rather than by clap, as TOML values for --print-config.
+fn built_in_default(id: &str) -> Option<String> {
+    match id {
+        "dev" => Some(toml_string(DEFAULT_DEVICE_NAME)),
+        "dir" => Some(toml_string(DEFAULT_DIR)),
+        "warmup_lines" => Some(toml_string(&WARMUP_LINE_COUNT.to_string())),
+        "salt_file" => Some(toml_string(&default_salt_file().display().to_string())),
+        "activities" => Some(format!(
+            "[{}]",
+            ACTIVITIES_ARR
+                .iter()
+                .map(|activity| toml_string(label_code(activity)))
+                .collect::<Vec<_>>()
+                .join(", ")
+        )),
+        _ => None,
+    }
+}
+
+fn toml_string(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn run(args: Args) -> Result<(), SessionError> {
+    match &args.command {
+        Some(Command::Validate { dir }) => {
+            return validate::run(dir).map_err(|e| SessionError::new(Failure::Validation, e));
+        }
+        Some(Command::Merge { dirs, out, pad }) => return Ok(merge::run(dirs, out, *pad)?),
+        Some(Command::Playback {
+            dir,
+            speed,
+            interval_ms,
+        }) => return Ok(playback::run(dir, *speed, u128::from(*interval_ms))?),
+        Some(Command::Scrub {
+            dir,
+            out,
+            drop,
+            hash,
+            salt_file,
+        }) => {
+            let salt_file = salt_file.clone().unwrap_or_else(default_salt_file);
+            return Ok(scrub::run(dir, out, drop, hash, &salt_file)?);
+        }
+        None => {}
+    }
+
+    let (args, argv, last_subject) = if args.repeat_last {
+        let last = LastSession::load(&args)?;
+        (last.args, last.argv, last.subject)
+    } else {
+        let argv = std::env::args_os()
+            .skip(1)
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect();
+        (args, argv, None)
+    };
+
+    #[cfg(feature = "upload")]
+    if let Some(url) = &args.upload {
+        upload::check_url(url, args.allow_insecure_upload)?;
+    }
+
+    SAFE_MODE.store(args.safe_mode, Ordering::Relaxed);
+    STDIN_SOURCE.store(
+        args.dev.as_deref().is_some_and(source::is_stdin),
+        Ordering::Relaxed,
+    );
+
+    let mut session_activities = if args.activities.is_empty() {
+        ACTIVITIES_ARR.to_vec()
+    } else {
+        args.activities.clone()
+    };
+    for excluded in &args.exclude {
+        let code = label_code(excluded);
+        if !session_activities.iter().any(|a| label_code(a) == code) {
+            eprintln!("--exclude: {:?} is not part of the session", excluded);
+        }
+        session_activities.retain(|a| label_code(a) != code);
+    }
+    check_activities(&session_activities, args.min_activities)?;
+
+    if let Some(font) = &args.font {
+        let figfont = load_font(font).unwrap_or_else(|e| {
+            eprintln!(
+                "Failed to load font '{}' ({}), using the standard one",
+                font, e
+            );
+            FIGfont::standard().expect("Failed to load FIGfont")
+        });
+        let _ = FIGFONT.set(figfont);
+    }
+
+    if let Some(lang) = &args.lang {
+        let _ = MESSAGES.set(Messages::load(lang)?);
+    }
+    if let Some(instructions) = &args.instructions {
+        let _ = INSTRUCTIONS.set(Messages::load(instructions)?);
+    }
+
+    if args.rehearse_countdown {
+        run_dry(&args, &session_activities, rehearse);
+        return Ok(());
+    }
+    if args.practice {
+        run_dry(&args, &session_activities, |_| {});
+        let answer = prompt_choice(
+            "Start the real recording now? (y/N): ",
+            &["y", "n"],
+            Some("n"),
+        )?;
+        if answer == "n" {
+            return Ok(());
+        }
+    }
 
     let dev = args
         .dev
         .clone()
         .unwrap_or_else(|| PathBuf::from(DEFAULT_DEVICE_NAME));
 
-    let port = SerialPort::open(&dev, BAUD).map_err(|e| {
-        if e.kind() == io::ErrorKind::NotFound {
-            io::Error::new(
-                io::ErrorKind::NotFound,
-                format!("Device not found: {}", DEFAULT_DEVICE_NAME),
+    let mut baud = args.baud;
+    let mut origin = Origin::Stdin;
+    let mut serial_line = None;
+    let line = SerialLine::from_args(&args);
+    let (source, dev): (Box<dyn Source>, PathBuf) = match dev.to_str().and_then(source::tcp_address)
+    {
+        address
+            if line != SerialLine::default()
+                && (address.is_some() || source::is_stdin(&dev) || source::is_fifo(&dev)) =>
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "--parity, --stop-bits and --flow-control only apply to serial ports, not '{}'",
+                    dev.display()
+                ),
             )
-        } else {
-            e
+            .into());
         }
-    })?;
+        _ if source::is_stdin(&dev) => (Box::new(PipeSource::stdin(READ_TIMEOUT)), dev),
+        _ if source::is_fifo(&dev) => {
+            println!("Waiting for a writer on {}", dev.display());
+            origin = Origin::Fifo;
+            (Box::new(PipeSource::fifo(&dev, READ_TIMEOUT)?), dev)
+        }
+        Some(address) => {
+            let stream = TcpSource::connect(address, READ_TIMEOUT)
+                .map_err(|e| SessionError::new(Failure::Device, e))?;
+            origin = Origin::Tcp(address.to_string());
+            (Box::new(stream), dev)
+        }
+        None => {
+            let (mut port, dev) = open_device(dev, args.baud, &line)?;
+            port.set_read_timeout(READ_TIMEOUT)?;
+            serial_line = Some(line);
+            if args.auto_baud {
+                baud = detect_baud(&mut port, args.expected_columns)
+                    .map_err(|e| SessionError::new(Failure::Device, e))?;
+                println!("Detected baud rate: {}", baud);
+            }
+            origin = Origin::Serial { baud, line };
+            (Box::new(port), dev)
+        }
+    };
+    let mut reader = io::BufReader::new(source);
+
+    if args.confirm_device {
+        confirm_device(&mut reader, &dev)?;
+    }
 
     let base_dir = args
         .dir
         .clone()
         .unwrap_or_else(|| PathBuf::from(DEFAULT_DIR));
     validate_dir(&base_dir)?;
+    let _lock = RecordingLock::acquire(&base_dir).map_err(|e| match e.kind() {
+        io::ErrorKind::ResourceBusy => SessionError::new(Failure::Validation, e),
+        _ => SessionError::from(e),
+    })?;
+
+    let operator = match &args.operators_file {
+        Some(path) => {
+            let allowed = read_operators_file(path)?;
+            match &args.operator {
+                Some(operator) if !allowed.contains(operator) => {
+                    return Err(SessionError::new(
+                        Failure::Validation,
+                        io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!(
+                                "operator '{}' is not listed in {}",
+                                operator,
+                                path.display()
+                            ),
+                        ),
+                    ));
+                }
+                Some(operator) => Some(operator.clone()),
+                None => {
+                    // prompt_choice lowercases the answer
+                    let choices: Vec<String> = allowed.iter().map(|id| id.to_lowercase()).collect();
+                    let choices: Vec<&str> = choices.iter().map(String::as_str).collect();
+                    let choice = prompt_choice("operator: ", &choices, None)?;
+                    allowed.into_iter().find(|id| id.to_lowercase() == choice)
+                }
+            }
+        }
+        None => args.operator.clone(),
+    };
+
+    let subject = if args.no_subject {
+        None
+    } else {
+        match last_subject {
+            Some(subject) if confirm_last_subject(&subject)? => Some(subject),
+            _ => Some(prompt_subject()?),
+        }
+    };
+
+    let placements: Vec<String> = args.placements.iter().map(|p| p.to_lowercase()).collect();
+    let placement = match &args.placement {
+        Some(placement) if placements.contains(&placement.to_lowercase()) => {
+            (placement.to_lowercase(), None)
+        }
+        Some(description) => ("other".to_string(), Some(description.replace('\n', " "))),
+        None => prompt_placement(&placements)?,
+    };
+
+    let order = if args.interactive_order {
+        session_activities = prompt_activity_order(&session_activities)?;
+        OrderPolicy::None
+    } else {
+        args.order
+    };
 
-    let sex = prompt_choice("sex (f/m): ", &["f", "m"], None)?;
-    let hand = prompt_choice("hand (l/R): ", &["l", "r"], Some("r"))?;
-    let height = prompt_height("height (in cm): ")?;
+    let contact = match (args.quality_field, args.quality_min) {
+        (Some(field), Some(min)) => Some(check_contact(&mut reader, field, min)?),
+        _ => None,
+    };
+
+    let session_start = now_ms();
+    let study_dir = match &args.study {
+        Some(study) if args.study_dir => base_dir.join(study),
+        _ => base_dir.clone(),
+    };
+    let parent_dir = match &args.dir_template {
+        Some(template) => study_dir.join(format_local_time(template, session_start)),
+        None => study_dir,
+    };
+    fs::create_dir_all(&parent_dir)?;
+    let participant = match &args.participant_id {
+        Some(id) if args.hash_id => {
+            let salt_file = args.salt_file.clone().unwrap_or_else(default_salt_file);
+            let hash = hash_participant_id(id, &load_or_create_salt(&salt_file)?);
+            Some(("participant_hash", hash))
+        }
+        Some(id) => Some(("participant_id", id.clone())),
+        None => None,
+    };
+    let dir_suffix = participant.as_ref().map(|(key, value)| match *key {
+        "participant_hash" => &value[..HASHED_ID_DIR_CHARS],
+        _ => value.as_str(),
+    });
 
-    let recording_dir = next_numeric_subdir(&base_dir)?;
+    let recording_dir = next_numeric_subdir(&parent_dir, dir_suffix)?;
     fs::create_dir(&recording_dir)?;
     println!(
         "New record: {}",
@@ -103,205 +1262,4003 @@ fn main() -> std::io::Result<()> {
 
     let char_file = recording_dir.join("chars.txt");
     let mut char_file = File::create(char_file)?;
-    let _ = writeln!(
-        char_file,
-        "sex={}\nhand={}\nheight={}",
-        sex,
-        hand,
-        height.unwrap_or("none".to_string())
-    );
+    match &subject {
+        Some(subject) => {
+            let _ = write_subject(&mut char_file, subject);
+        }
+        None => {
+            let _ = writeln!(char_file, "anonymous=true");
+        }
+    }
+    if let Some((key, value)) = &participant {
+        let _ = writeln!(char_file, "{}={}", key, value);
+    }
+    if let Some(operator) = &operator {
+        let _ = writeln!(char_file, "operator={}", operator);
+    }
+    let _ = writeln!(char_file, "placement={}", placement.0);
+    if let Some(description) = &placement.1 {
+        let _ = writeln!(char_file, "placement_other={}", description);
+    }
+    if args.auto_baud {
+        let _ = writeln!(char_file, "baud_detected={}", baud);
+    }
+    if let Some(line) = &serial_line {
+        let _ = writeln!(char_file, "serial_frame={}", line.frame());
+        let _ = writeln!(
+            char_file,
+            "flow_control={}",
+            line.flow_control.to_possible_value().unwrap().get_name()
+        );
+    }
+    if let Some(study) = &args.study {
+        let _ = writeln!(char_file, "study={}", study);
+    }
+    if let Some((quality, overridden)) = contact {
+        let _ = writeln!(char_file, "contact_quality={:.3}", quality);
+        if overridden {
+            let _ = writeln!(char_file, "contact_override=true");
+        }
+    }
+    let seed = args.seed.unwrap_or_else(rand::random);
+    let _ = writeln!(char_file, "seed={}", seed);
+    if let Some(jitter_ms) = args.duration_jitter_ms {
+        let _ = writeln!(char_file, "duration_jitter_ms={}", jitter_ms);
+    }
+    if let Some((min, max)) = args.clip_range {
+        let _ = writeln!(char_file, "clip_range={}:{}", min, max);
+    }
+    if let Some(secs) = args.baseline_secs {
+        let _ = writeln!(char_file, "baseline_secs={}", secs);
+        let _ = writeln!(
+            char_file,
+            "baseline_label={}",
+            label_code(&args.baseline_label)
+        );
+    }
+    if let Some(secs) = args.cooldown_secs {
+        let _ = writeln!(char_file, "cooldown_secs={}", secs);
+        let _ = writeln!(
+            char_file,
+            "cooldown_label={}",
+            label_code(&args.cooldown_label)
+        );
+    }
+    if args.interactive_order {
+        let codes: Vec<&str> = session_activities.iter().map(label_code).collect();
+        let _ = writeln!(char_file, "order=interactive");
+        let _ = writeln!(char_file, "activity_order={}", codes.join(","));
+    } else {
+        let _ = writeln!(
+            char_file,
+            "order={}",
+            args.order.to_possible_value().unwrap().get_name()
+        );
+    }
+    let _ = writeln!(
+        char_file,
+        "first_line={}",
+        args.first_line.to_possible_value().unwrap().get_name()
+    );
+    if let Some(policy) = args.backwards_timestamps {
+        let _ = writeln!(
+            char_file,
+            "backwards_timestamps={}",
+            policy.to_possible_value().unwrap().get_name()
+        );
+    }
+    if !args.exclude.is_empty() {
+        let codes: Vec<&str> = args.exclude.iter().map(label_code).collect();
+        let _ = writeln!(char_file, "excluded={}", codes.join(","));
+    }
+    for (key, value) in &args.env_tags {
+        let _ = writeln!(char_file, "env.{}={}", key, value);
+    }
+    // the options as given, one per line, for --repeat-last
+    let argv = redact_identifiers(&argv);
+    let _ = writeln!(char_file, "arg_count={}", argv.len());
+    for arg in argv.iter().filter(|arg| !arg.contains('\n')) {
+        let _ = writeln!(char_file, "arg={}", arg);
+    }
+
+    let label_file_path = recording_dir.join("labels.csv");
+    if args.numeric_labels {
+        let mapping: Vec<String> = ALL_ACTIVITIES
+            .iter()
+            .map(|activity| format!("{}:{}", label_code(activity), label_id(activity)))
+            .collect();
+        let _ = writeln!(char_file, "label_ids={}", mapping.join(","));
+    }
+
+    // timestamps written to the recording are shifted back by this much
+    let time_origin = if args.relative_time {
+        let _ = writeln!(char_file, "time_origin_ms={}", session_start);
+        session_start
+    } else {
+        0
+    };
+
+    let mut labels_csv = File::create(label_file_path)?;
+    let label_style = if args.numeric_labels {
+        LabelStyle::Id
+    } else if args.label_names {
+        writeln!(labels_csv, "{}", LABELS_HEADER)?;
+        LabelStyle::Name
+    } else {
+        LabelStyle::Code
+    };
+    #[cfg(feature = "sqlite")]
+    let database = match &args.sqlite {
+        Some(path) => {
+            let database = sqlite::Database::open(
+                path,
+                &recording_dir.display().to_string(),
+                session_start.saturating_sub(time_origin),
+            )?;
+            let _ = writeln!(char_file, "sqlite_session={}", database.id());
+            Some(Arc::new(database))
+        }
+        None => None,
+    };
+    let embedded_subject = subject.as_ref().filter(|_| args.embed_subject);
+    if embedded_subject.is_some() {
+        let _ = writeln!(char_file, "embed_subject=true");
+    }
+    let readings = ReadingsWriter::create(&recording_dir, &args, time_origin, embedded_subject)?;
+    #[cfg(feature = "sqlite")]
+    let readings = ReadingsWriter {
+        database: database.clone(),
+        ..readings
+    };
+    // shared with the activity thread, which writes --inline-labels markers into it
+    let readings = Arc::new(Mutex::new(readings));
+
+    let label_file = Arc::new(LabelFile {
+        file: Mutex::new(Box::new(labels_csv)),
+        style: label_style,
+        time_origin,
+        readings: args.inline_labels.then(|| Arc::clone(&readings)),
+        #[cfg(feature = "sqlite")]
+        database: database.clone(),
+    });
+    let activity_label_file = Arc::clone(&label_file);
+
+    let mut events_file = if args.events {
+        Some(File::create(recording_dir.join("events.csv"))?)
+    } else {
+        None
+    };
+
+    let mut transition_log = match &args.transition_log {
+        Some(path) => Some(OpenOptions::new().create(true).append(true).open(path)?),
+        None => None,
+    };
+
+    let protocol = Protocol::new(&args, session_activities, order, seed);
+
+    let state = Arc::new(SessionState::new());
+    let activity_state = Arc::clone(&state);
+
+    let activity_thread = if args.raw_log {
+        if let Some(label) = &args.raw_log_label {
+            set_label(label, &[], &label_file, &state)?;
+        }
+        println!("Logging readings, press Ctrl+C to stop.");
+        let _ = writeln!(char_file, "raw_log=true");
+        None
+    } else {
+        Some(thread::spawn(move || {
+            // labels only start once the warm-up is accepted, so a retried warm-up
+            // doesn't leave them running ahead of the readings
+            if activity_state.wait_for_warmup() {
+                run_protocol(
+                    &protocol,
+                    &activity_label_file,
+                    &activity_state,
+                    events_file.as_mut(),
+                    transition_log.as_mut(),
+                );
+            }
+        }))
+    };
+
+    let mut averager = args.average.map(|n| Averager::new(n as usize));
+    let mut normalizer = args
+        .normalize
+        .then(|| Normalizer::new(args.decimals, args.expected_columns));
+
+    let mut warmup = match (args.warmup_secs, args.warmup_stable_lines) {
+        (Some(duration), _) => Warmup::new(WarmupLimit::Time(duration)),
+        (None, Some(lines)) => Warmup::new(WarmupLimit::Stable {
+            lines,
+            timeout: args.warmup_timeout_secs,
+        }),
+        (None, None) => Warmup::new(WarmupLimit::Lines(
+            args.warmup_lines.unwrap_or(WARMUP_LINE_COUNT),
+        )),
+    };
+    state.warmed_up.store(warmup.done, Ordering::Relaxed);
+    // checked on the lines after the warm-up until one is recorded
+    let mut first_line = Some(args.first_line);
+    let mut first_lines_dropped = 0;
+    let mut counter = 0;
+    let mut flush_policy = FlushPolicy::new(args.flush_target_ms.map(Duration::from_millis));
+    let mut clip_detector = args
+        .clip_range
+        .map(|(min, max)| ClipDetector::new(min, max, args.clip_warn_percent));
+    let mut stats = Stats::default();
+    let mut last_sample_at: Option<u128> = None;
+    let mut last_data_at = now_ms();
+    let mut watchdog_armed_at: Option<u128> = None;
+    let mut empty_run: u64 = 0;
+    let mut last_heartbeat_at = now_ms();
+    let mut last_flush_at = now_ms();
+
+    let mut raw = Vec::new();
+
+    // from here on Ctrl+C stops recording gracefully instead of killing the process
+    let interrupted = Arc::new(AtomicBool::new(false));
+    for signal in [signal_hook::consts::SIGINT, signal_hook::consts::SIGTERM] {
+        signal_hook::flag::register(signal, Arc::clone(&interrupted))?;
+    }
+
+    let mut hard_stopped = false;
+    // set by the watchdog and --max-empty-lines, which end the session with a failure
+    // once the recording is cleaned up like after a hard stop
+    let mut device_abort = None;
+    while !state.done.load(Ordering::Relaxed) && !interrupted.load(Ordering::Relaxed) {
+        if let Some(max_secs) = args.max_session_secs
+            && now_ms().saturating_sub(session_start) >= u128::from(max_secs) * 1000
+        {
+            hard_stopped = true;
+            break;
+        }
+        if let Some(heartbeat_secs) = args.heartbeat_secs {
+            let now = now_ms();
+            if now.saturating_sub(last_heartbeat_at) >= u128::from(heartbeat_secs) * 1000 {
+                lock_readings(&readings).write_heartbeat(now)?;
+                last_heartbeat_at = now;
+            }
+        }
+        if let Some(watchdog_ms) = args.watchdog_ms {
+            let now = now_ms();
+            if state.is_active() {
+                let armed_at = *watchdog_armed_at.get_or_insert(now);
+                let silent_for = now.saturating_sub(last_data_at.max(armed_at));
+                if silent_for > watchdog_ms {
+                    eprintln!(
+                        "\x07\nWATCHDOG: no data from the device for {}ms during {:?}, aborting",
+                        silent_for,
+                        state.activity()
+                    );
+                    let _ = writeln!(char_file, "watchdog_abort=true");
+                    device_abort = Some(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "device stopped sending data",
+                    ));
+                    break;
+                }
+            } else {
+                watchdog_armed_at = None;
+            }
+        }
+
+        // set when the source went away and --reconnect may bring it back
+        let mut device_lost = false;
+        match read_device_line(&mut reader, &mut raw) {
+            Ok((0, _)) if args.reconnect.is_some() && !matches!(origin, Origin::Stdin) => {
+                device_lost = true;
+            }
+            Ok((0, _)) => {
+                break;
+            }
+            Ok((_, Ok(line))) => {
+                last_data_at = now_ms();
+                if !warmup.done {
+                    let valid = is_valid_reading(line, args.expected_columns);
+                    if warmup.observe(valid) {
+                        finish_warmup(&mut warmup, &args, baud, &mut char_file)?;
+                        state.warmed_up.store(warmup.done, Ordering::Relaxed);
+                    }
+                } else if first_line.is_some_and(|policy| {
+                    !policy.accepts(line, first_lines_dropped, args.expected_columns)
+                }) {
+                    first_lines_dropped += 1;
+                } else if line.trim().is_empty() {
+                    // the device is sending, just nothing usable, so the watchdog won't fire
+                    if !state.is_active() {
+                        empty_run = 0;
+                    } else if let Some(max_empty) = args.max_empty_lines {
+                        empty_run += 1;
+                        if empty_run >= max_empty {
+                            eprintln!(
+                                "\x07\nEMPTY LINES: {} empty lines in a row from the device during {:?}, aborting",
+                                empty_run,
+                                state.activity()
+                            );
+                            let _ = writeln!(char_file, "empty_lines_abort=true");
+                            device_abort = Some(io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                "device sends only empty lines",
+                            ));
+                            break;
+                        }
+                    }
+                } else {
+                    empty_run = 0;
+                    if first_line.take().is_some() && first_lines_dropped > 0 {
+                        let _ = writeln!(char_file, "first_lines_dropped={}", first_lines_dropped);
+                    }
+                    let line = match &mut normalizer {
+                        Some(normalizer) => normalizer.normalize(line, &mut stats),
+                        None => line,
+                    };
+                    // held until the line is written, so --inline-labels markers can't come
+                    // between taking its timestamp and writing it
+                    let mut readings = lock_readings(&readings);
+                    let mut now = now_ms();
+                    if let (Some(policy), Some(last)) = (args.backwards_timestamps, last_sample_at)
+                        && now < last
+                    {
+                        stats.backwards_timestamps += 1;
+                        match policy {
+                            BackwardsTimestamps::Clamp => now = last,
+                            BackwardsTimestamps::Flag => {
+                                let previous = last.to_string();
+                                let inline = args.inline_labels.then_some(&mut *readings);
+                                let _ = write_label_row_with(
+                                    now,
+                                    "#clock_backwards",
+                                    &[&previous],
+                                    &label_file,
+                                    inline,
+                                );
+                            }
+                        }
+                    }
+                    if let (Some(max_gap), Some(last)) = (args.max_gap_ms, last_sample_at)
+                        && now.saturating_sub(last) > max_gap
+                    {
+                        stats.record_gap(last, now - last);
+                        if args.gap_labels {
+                            let gap = (now - last).to_string();
+                            let inline = args.inline_labels.then_some(&mut *readings);
+                            let _ =
+                                write_label_row_with(last, "#gap", &[&gap], &label_file, inline);
+                        }
+                    }
+                    if args.fill_gaps
+                        && let (Some(hz), Some(last)) = (args.expected_hz, last_sample_at)
+                    {
+                        let columns = split_reading_fields(line).count().max(1);
+                        let filler = format!("{}\n", vec!["NaN"; columns].join(";"));
+                        for timestamp in missing_timestamps(last, now, hz) {
+                            readings.write_sample(&state, timestamp, &filler)?;
+                            stats.filled_rows += 1;
+                        }
+                    }
+                    last_sample_at = Some(now);
+
+                    if stats.samples == 0 && !args.quiet {
+                        let _ = announce_recording();
+                    }
+                    if stats.samples < args.debug_first {
+                        eprintln!(
+                            "debug #{}: raw {:?} -> {:?}",
+                            stats.samples + 1,
+                            line,
+                            parse_reading_fields(line)
+                        );
+                    }
+
+                    match &mut averager {
+                        Some(averager) => {
+                            for (timestamp, row) in averager.push(now, line) {
+                                readings.write_sample(&state, timestamp, &row)?;
+                            }
+                        }
+                        None => readings.write_sample(&state, now, line)?,
+                    }
+                    stats.count_sample(&state.activity());
+                    state.count_sample();
+                    if let Some(detector) = &mut clip_detector {
+                        detector.check(line, now, &mut stats.clipped);
+                    }
+                    if counter > flush_policy.every
+                        || now.saturating_sub(last_flush_at) >= FLUSH_INTERVAL_MS
+                    {
+                        let started = Instant::now();
+                        readings.flush()?;
+                        flush_policy.adapt(started.elapsed());
+                        counter = 0;
+                        last_flush_at = now;
+                    }
+                }
+            }
+            Ok((_, Err(lossy))) => {
+                last_data_at = now_ms();
+                // not UTF-8, most likely a baud mismatch while still warming up
+                if !warmup.done {
+                    if warmup.observe(false) {
+                        finish_warmup(&mut warmup, &args, baud, &mut char_file)?;
+                        state.warmed_up.store(warmup.done, Ordering::Relaxed);
+                    }
+                } else {
+                    stats.invalid_utf8 += 1;
+                    if stats.invalid_utf8 <= INVALID_LINES_REPORTED {
+                        eprintln!("Dropped a line that is not valid UTF-8: {:?}", lossy);
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+                // keep the partial line, the rest of it comes with the next read
+                continue;
+            }
+            Err(_) => {
+                stats.read_errors += 1;
+                device_lost = args.reconnect.is_some() && origin.is_gone(&dev);
+            }
+        }
+        if device_lost {
+            // a serial port is held exclusively, so the old one is closed before reopening
+            *reader.get_mut() = Box::new(io::empty());
+            reader.consume(reader.buffer().len());
+            let max = args.reconnect.unwrap_or(0);
+            let Some(source) = reconnect(&origin, &dev, stats.reconnects, max) else {
+                break;
+            };
+            *reader.get_mut() = source;
+            stats.reconnects += 1;
+            warmup.rerun();
+            let now = now_ms();
+            last_data_at = now;
+            let count = stats.reconnects.to_string();
+            let mut readings = lock_readings(&readings);
+            let inline = args.inline_labels.then_some(&mut *readings);
+            let _ = write_label_row_with(now, "#reconnect", &[&count], &label_file, inline);
+            raw.clear();
+            continue;
+        }
+        raw.clear();
+        counter += 1;
+    }
+
+    if let Some((timestamp, row)) = averager.as_mut().and_then(Averager::finish) {
+        lock_readings(&readings).write_sample(&state, timestamp, &row)?;
+    }
+    stats.flush_every = flush_policy.target.map(|_| flush_policy.every);
+    // set by the activity thread if the protocol ran to its end
+    let protocol_done = state.done.load(Ordering::Relaxed);
+    // the device went quiet, don't leave the activity thread waiting for samples
+    state.done.store(true, Ordering::Relaxed);
+    let interrupted = interrupted.load(Ordering::Relaxed);
+    let participant_abort = state.participant_abort.load(Ordering::Relaxed);
+    let ended_cleanly = !hard_stopped
+        && device_abort.is_none()
+        && !participant_abort
+        && if args.raw_log {
+            interrupted
+        } else {
+            protocol_done && !interrupted
+        };
+    if hard_stopped || device_abort.is_some() {
+        // the activity thread may be stuck or mid-activity, so it's left behind rather
+        // than joined
+        if !safe_mode() {
+            let _ = terminal::disable_raw_mode();
+            let _ = execute!(io::stdout(), cursor::Show);
+        }
+        if hard_stopped {
+            eprintln!(
+                "\nHARD STOP: session reached --max-session-secs {}, recording stopped.",
+                args.max_session_secs.unwrap_or_default()
+            );
+            let _ = writeln!(char_file, "hard_stop=true");
+        }
+    } else if interrupted && args.raw_log {
+        println!("\nRecording stopped.");
+    } else if interrupted {
+        if !safe_mode() {
+            let _ = execute!(io::stdout(), cursor::Show);
+        }
+        println!("\nInterrupted, recording stopped.");
+        let _ = writeln!(char_file, "aborted_by=operator");
+    } else {
+        if let Some(activity_thread) = activity_thread {
+            let _ = activity_thread.join();
+        }
+        if participant_abort {
+            println!("\nThe participant ended the session, recording stopped.");
+            let _ = writeln!(char_file, "aborted_by=participant");
+        }
+    }
+    if ended_cleanly {
+        write_label_annotation(END_MARKER, now_ms(), &[], &label_file)?;
+    }
+    {
+        // only now, the activity thread's last labels and the #end marker go into the
+        // readings too with --inline-labels
+        let mut readings = lock_readings(&readings);
+        readings.finish()?;
+        if !readings.chunks.is_empty() {
+            let _ = writeln!(char_file, "readings_chunks={}", readings.chunks.join(","));
+        }
+    }
+    for choice in state
+        .choices
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .iter()
+    {
+        let _ = writeln!(char_file, "choice={}", choice);
+    }
+    if args.labels_json {
+        write_labels_json(&recording_dir, now_ms().saturating_sub(time_origin))?;
+    }
+    #[cfg(feature = "sqlite")]
+    if let Some(database) = &database {
+        let metadata = fs::read_to_string(recording_dir.join("chars.txt"))?;
+        database.finish(now_ms().saturating_sub(time_origin), &metadata);
+    }
+
+    let (sex, hand, height) = match &subject {
+        Some(subject) => (
+            subject.sex.as_str(),
+            subject.hand.as_str(),
+            subject.height.as_deref().unwrap_or_default(),
+        ),
+        None => ("", "", ""),
+    };
+    let index_row = format!(
+        "{};{};{};{};{};{};{};{}",
+        recording_dir
+            .strip_prefix(&base_dir)
+            .unwrap_or(&recording_dir)
+            .display(),
+        session_start,
+        sex,
+        hand,
+        height,
+        stats.samples,
+        operator.as_deref().unwrap_or_default(),
+        args.study.as_deref().unwrap_or_default()
+    );
+    append_to_index(&base_dir, &index_row)?;
+
+    if args.zip {
+        let archive = recording_dir.with_extension("zip");
+        zip::archive_dir(&recording_dir, &archive)?;
+        if args.zip_remove {
+            fs::remove_dir_all(&recording_dir)?;
+        }
+        println!("Archived to {}", archive.display());
+    }
+
+    #[cfg(feature = "upload")]
+    if let Some(url) = &args.upload {
+        let archive = recording_dir.with_extension("zip");
+        if !args.zip {
+            zip::archive_dir(&recording_dir, &archive)?;
+        }
+        let result = upload::post_file(url, &archive);
+        if !args.zip {
+            let _ = fs::remove_file(&archive);
+        }
+        match result {
+            Ok(status) => {
+                println!("Uploaded to {} ({})", url, status);
+                let _ = writeln!(char_file, "upload=ok");
+            }
+            Err(e) => {
+                eprintln!("Upload to {} failed: {}", url, e);
+                let _ = writeln!(char_file, "upload=failed");
+            }
+        }
+    }
+
+    stats.duration = Duration::from_millis(now_ms().saturating_sub(session_start) as u64);
+    print_summary(&stats, &args);
+    if let Some(path) = &args.summary_json {
+        write_summary_json(
+            path,
+            &stats,
+            args.max_gap_ms,
+            hard_stopped
+                || device_abort.is_some()
+                || (interrupted && !args.raw_log)
+                || participant_abort,
+        )?;
+    }
+
+    if let Some(e) = device_abort {
+        return Err(SessionError::new(Failure::Device, e));
+    }
+
+    // Ctrl+C is how a --raw-log recording normally ends
+    if interrupted && !args.raw_log {
+        return Err(SessionError::new(
+            Failure::UserAbort,
+            io::Error::new(io::ErrorKind::Interrupted, "session interrupted"),
+        ));
+    }
+    if participant_abort {
+        return Err(SessionError::new(
+            Failure::ParticipantAbort,
+            io::Error::new(
+                io::ErrorKind::Interrupted,
+                "session ended by the participant",
+            ),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Advisory lock on the base directory, held for the whole session so a second
+/// collector can't record into the same place. The lock file holds the owner's PID
+/// and is removed when the lock is dropped.
+struct RecordingLock {
+    path: PathBuf,
+    _file: File,
+}
+
+impl RecordingLock {
+    fn acquire(base_dir: &Path) -> io::Result<Self> {
+        let path = base_dir.join(LOCK_FILE_NAME);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+
+        #[cfg(unix)]
+        {
+            use std::os::fd::AsRawFd;
+            if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == -1 {
+                let e = io::Error::last_os_error();
+                if e.kind() != io::ErrorKind::WouldBlock {
+                    return Err(e);
+                }
+                let mut holder = String::new();
+                file.read_to_string(&mut holder)?;
+                return Err(io::Error::new(
+                    io::ErrorKind::ResourceBusy,
+                    format!(
+                        "'{}' is already in use by another collector (pid {})",
+                        base_dir.display(),
+                        holder.trim()
+                    ),
+                ));
+            }
+        }
+
+        file.set_len(0)?;
+        write!(file, "{}", std::process::id())?;
+        Ok(RecordingLock { path, _file: file })
+    }
+}
+
+impl Drop for RecordingLock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Where recorded samples go: readings.csv, or with --split-by-activity a
+/// readings_<label>_<window>.csv per label window, opened when its first sample arrives.
+struct ReadingsWriter {
+    dir: PathBuf,
+    split: bool,
+    format: OutputFormat,
+    window: usize,
+    /// The open file, written as `<path>.partial` until it is closed.
+    writer: Option<BufWriter<File>>,
+    path: Option<PathBuf>,
+    /// Bytes written to the open file.
+    bytes: u64,
+    max_file_bytes: Option<u64>,
+    /// Names of the files written with --max-file-bytes, in order.
+    chunks: Vec<String>,
+    /// Reused for formatting each record.
+    buf: String,
+    checksum_every: Option<u64>,
+    /// Sync the file to disk after every flush.
+    fsync: bool,
+    /// Subtracted from every timestamp, see --relative-time.
+    time_origin: u128,
+    /// CRC of the rows written since the last checkpoint line.
+    crc: Crc32,
+    since_checkpoint: u64,
+    /// Rows waiting for the next record batch of the open file, with --format arrow.
+    #[cfg(feature = "arrow")]
+    arrow: Option<arrow::ArrowFile>,
+    #[cfg(feature = "arrow")]
+    expected_columns: Option<usize>,
+    /// Also gets every row, committed on flush, with --sqlite.
+    #[cfg(feature = "sqlite")]
+    database: Option<Arc<sqlite::Database>>,
+    /// Timestamp of the last row written, which --inline-labels markers don't go below.
+    last_timestamp: u128,
+    /// `;sex;hand;height` appended to every row with --embed-subject, empty otherwise.
+    row_suffix: String,
+}
+
+impl ReadingsWriter {
+    fn create(
+        dir: &Path,
+        args: &Args,
+        time_origin: u128,
+        subject: Option<&Subject>,
+    ) -> io::Result<Self> {
+        #[cfg(feature = "arrow")]
+        if args.format == OutputFormat::Arrow
+            && (args.checksum_every.is_some()
+                || args.heartbeat_secs.is_some()
+                || args.max_file_bytes.is_some())
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "--format arrow can't be combined with --checksum-every, --heartbeat-secs \
+                 or --max-file-bytes",
+            ));
+        }
+        if args.inline_labels && args.format != OutputFormat::Csv {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "--inline-labels needs --format csv",
+            ));
+        }
+        if args.embed_subject && args.format != OutputFormat::Csv {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "--embed-subject needs --format csv",
+            ));
+        }
+        let mut readings = ReadingsWriter {
+            dir: dir.to_path_buf(),
+            split: args.split_by_activity,
+            format: args.format,
+            window: 0,
+            writer: None,
+            path: None,
+            bytes: 0,
+            max_file_bytes: args.max_file_bytes,
+            chunks: Vec::new(),
+            buf: String::new(),
+            checksum_every: args.checksum_every,
+            fsync: args.fsync,
+            time_origin,
+            crc: Crc32::new(),
+            since_checkpoint: 0,
+            #[cfg(feature = "arrow")]
+            arrow: None,
+            #[cfg(feature = "arrow")]
+            expected_columns: args.expected_columns,
+            #[cfg(feature = "sqlite")]
+            database: None,
+            last_timestamp: 0,
+            row_suffix: subject.map_or_else(String::new, |subject| {
+                format!(
+                    ";{};{};{}",
+                    subject.sex,
+                    subject.hand,
+                    subject.height.as_deref().unwrap_or_default()
+                )
+            }),
+        };
+        if readings.max_file_bytes.is_some() {
+            readings.open_chunk()?;
+        } else if !readings.split {
+            readings.open(&format!("readings.{}", args.format.extension()))?;
+        }
+        Ok(readings)
+    }
+
+    fn open(&mut self, name: &str) -> io::Result<()> {
+        let path = self.dir.join(name);
+        self.writer = Some(BufWriter::new(File::create(partial_path(&path))?));
+        self.path = Some(path);
+        self.bytes = 0;
+        #[cfg(feature = "arrow")]
+        if self.format == OutputFormat::Arrow {
+            self.arrow = Some(arrow::ArrowFile::new(self.expected_columns));
+        }
+        Ok(())
+    }
+
+    /// Opens the next readings.<n>.csv of a --max-file-bytes recording.
+    fn open_chunk(&mut self) -> io::Result<()> {
+        let name = format!(
+            "readings.{:03}.{}",
+            self.chunks.len(),
+            self.format.extension()
+        );
+        self.open(&name)?;
+        self.chunks.push(name);
+        Ok(())
+    }
+
+    /// Writes to the open file, keeping count of its size.
+    fn write_raw(&mut self, bytes: &[u8]) -> io::Result<()> {
+        if let Some(writer) = &mut self.writer {
+            writer.write_all(bytes)?;
+            self.bytes += bytes.len() as u64;
+        }
+        Ok(())
+    }
+
+    /// Flushes the open file and renames it into place, so a file without the
+    /// `.partial` suffix is always complete.
+    fn close(&mut self) -> io::Result<()> {
+        #[cfg(feature = "arrow")]
+        if let (Some(writer), Some(mut arrow)) = (&mut self.writer, self.arrow.take()) {
+            arrow.finish(writer)?;
+        }
+        if let (Some(writer), Some(path)) = (self.writer.take(), self.path.take()) {
+            let file = writer.into_inner().map_err(|e| e.into_error())?;
+            if self.fsync {
+                file.sync_all()?;
+            }
+            fs::rename(partial_path(&path), path)?;
+        }
+        Ok(())
+    }
+
+    fn write_sample(
+        &mut self,
+        state: &SessionState,
+        timestamp: u128,
+        line: &str,
+    ) -> io::Result<()> {
+        let timestamp = timestamp.saturating_sub(self.time_origin);
+        self.last_timestamp = self.last_timestamp.max(timestamp);
+        #[cfg(feature = "sqlite")]
+        if let Some(database) = &self.database {
+            database.insert_reading(timestamp, line.trim_end());
+        }
+        self.buf.clear();
+        match self.format {
+            OutputFormat::Csv if self.row_suffix.is_empty() => {
+                self.buf.push_str(&timestamp.to_string());
+                self.buf.push(';');
+                self.buf.push_str(line);
+            }
+            OutputFormat::Csv => {
+                self.buf.push_str(&timestamp.to_string());
+                self.buf.push(';');
+                self.buf.push_str(line.trim_end());
+                self.buf.push_str(&self.row_suffix);
+                self.buf.push('\n');
+            }
+            OutputFormat::Ndjson => {
+                push_json_reading(&mut self.buf, timestamp, line.trim_end());
+                self.buf.push('\n');
+            }
+            #[cfg(feature = "arrow")]
+            OutputFormat::Arrow => {
+                self.switch_window(state)?;
+                let fields: Vec<f64> = split_reading_fields(line)
+                    .map(|field| field.parse().unwrap_or(f64::NAN))
+                    .collect();
+                let arrow = self.arrow.as_mut().expect("arrow file is open");
+                arrow.push(timestamp, &fields);
+                return Ok(());
+            }
+        }
+
+        self.switch_window(state)?;
+        if let Some(max) = self.max_file_bytes
+            && self.bytes > 0
+            && self.bytes + self.buf.len() as u64 > max
+        {
+            self.write_checkpoint()?;
+            self.close()?;
+            self.open_chunk()?;
+        }
+        let writer = self.writer.as_mut().expect("readings writer is open");
+        writer.write_all(self.buf.as_bytes())?;
+        self.bytes += self.buf.len() as u64;
+
+        if let Some(every) = self.checksum_every {
+            self.crc.update(self.buf.as_bytes());
+            self.since_checkpoint += 1;
+            if self.since_checkpoint >= every {
+                self.write_checkpoint()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes a `#heartbeat;<timestamp>` line and flushes it, so the file keeps growing
+    /// while the device is idle. Not covered by checksums.
+    fn write_heartbeat(&mut self, timestamp: u128) -> io::Result<()> {
+        let timestamp = timestamp.saturating_sub(self.time_origin);
+        self.write_raw(format!("#heartbeat;{}\n", timestamp).as_bytes())?;
+        self.flush()
+    }
+
+    /// Writes a labels.csv row as an --inline-labels marker: `<timestamp>;#label;<label>`,
+    /// or the row as is for `#` annotations. Not covered by checksums.
+    fn write_marker(&mut self, timestamp: u128, code: &str, extra: &[&str]) -> io::Result<()> {
+        // a label's time is taken before the lock, and a #gap is dated back to where the
+        // gap started, so either may be behind the rows already written
+        let timestamp = timestamp
+            .saturating_sub(self.time_origin)
+            .max(self.last_timestamp);
+        self.last_timestamp = timestamp;
+        let mut row = match code.starts_with('#') {
+            true => format!("{};{}", timestamp, code),
+            false => format!("{};#label;{}", timestamp, code),
+        };
+        for field in extra {
+            row.push(';');
+            row.push_str(field);
+        }
+        row.push('\n');
+        self.write_raw(row.as_bytes())
+    }
+
+    /// Writes a `#crc32=<hex>;samples=<n>` line for the rows since the previous one.
+    fn write_checkpoint(&mut self) -> io::Result<()> {
+        if self.since_checkpoint == 0 {
+            return Ok(());
+        }
+        let line = format!(
+            "#crc32={:08x};samples={}\n",
+            self.crc.value(),
+            self.since_checkpoint
+        );
+        self.write_raw(line.as_bytes())?;
+        self.crc = Crc32::new();
+        self.since_checkpoint = 0;
+        Ok(())
+    }
+
+    /// With --split-by-activity, moves on to a new file when the label window changed.
+    fn switch_window(&mut self, state: &SessionState) -> io::Result<()> {
+        if self.split {
+            let label = state.label();
+            if self.writer.is_none() || label.window != self.window {
+                self.write_checkpoint()?;
+                self.close()?;
+                let name = format!(
+                    "readings_{}_{:03}.{}",
+                    label_code(&label.activity),
+                    label.window,
+                    self.format.extension()
+                );
+                self.open(&name)?;
+                self.window = label.window;
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        #[cfg(feature = "sqlite")]
+        if let Some(database) = &self.database {
+            database.commit();
+        }
+        match &mut self.writer {
+            Some(writer) => {
+                #[cfg(feature = "arrow")]
+                if let Some(arrow) = &mut self.arrow {
+                    arrow.write_batch(writer)?;
+                }
+                writer.flush()?;
+                if self.fsync {
+                    writer.get_ref().sync_all()?;
+                }
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Covers the trailing rows with a last checkpoint and closes the file.
+    fn finish(&mut self) -> io::Result<()> {
+        self.write_checkpoint()?;
+        self.close()
+    }
+}
+
+fn lock_readings(readings: &Mutex<ReadingsWriter>) -> MutexGuard<'_, ReadingsWriter> {
+    readings.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn partial_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".partial");
+    PathBuf::from(name)
+}
+
+fn push_json_reading(buf: &mut String, timestamp: u128, line: &str) {
+    use std::fmt::Write;
+
+    let _ = write!(buf, "{{\"t\":{}", timestamp);
+    match parse_reading_fields(line) {
+        Some(fields) => {
+            buf.push_str(",\"fields\":[");
+            for (i, field) in fields.iter().enumerate() {
+                if i > 0 {
+                    buf.push(',');
+                }
+                if field.is_finite() {
+                    let _ = write!(buf, "{}", field);
+                } else {
+                    buf.push_str("null");
+                }
+            }
+            buf.push(']');
+        }
+        None => {
+            buf.push_str(",\"raw\":");
+            push_json_string(buf, line);
+        }
+    }
+    buf.push('}');
+}
+
+fn push_json_string(buf: &mut String, s: &str) {
+    use std::fmt::Write;
+
+    buf.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => buf.push_str("\\\""),
+            '\\' => buf.push_str("\\\\"),
+            '\n' => buf.push_str("\\n"),
+            '\r' => buf.push_str("\\r"),
+            '\t' => buf.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(buf, "\\u{:04x}", c as u32);
+            }
+            c => buf.push(c),
+        }
+    }
+    buf.push('"');
+}
+
+/// Averages groups of `size` numeric readings into one row stamped with the midpoint of
+/// the group's timestamps. A line that isn't numeric, or has a different column count
+/// than the group, closes the current group early; non-numeric lines are passed through
+/// unchanged.
+struct Averager {
+    size: usize,
+    count: usize,
+    sums: Vec<f64>,
+    first: u128,
+    last: u128,
+}
+
+impl Averager {
+    fn new(size: usize) -> Self {
+        Averager {
+            size,
+            count: 0,
+            sums: Vec::new(),
+            first: 0,
+            last: 0,
+        }
+    }
+
+    /// Adds a reading and returns the rows that are ready to be written.
+    fn push(&mut self, timestamp: u128, line: &str) -> Vec<(u128, String)> {
+        let mut ready = Vec::new();
+        let Some(fields) = parse_reading_fields(line) else {
+            ready.extend(self.finish());
+            ready.push((timestamp, line.to_string()));
+            return ready;
+        };
+
+        if self.count > 0 && fields.len() != self.sums.len() {
+            ready.extend(self.finish());
+        }
+        if self.count == 0 {
+            self.sums = vec![0.0; fields.len()];
+            self.first = timestamp;
+        }
+        for (sum, field) in self.sums.iter_mut().zip(&fields) {
+            *sum += field;
+        }
+        self.last = timestamp;
+        self.count += 1;
+
+        if self.count == self.size {
+            ready.extend(self.finish());
+        }
+        ready
+    }
+
+    /// Emits the mean of the (possibly incomplete) current group.
+    fn finish(&mut self) -> Option<(u128, String)> {
+        if self.count == 0 {
+            return None;
+        }
+        let means: Vec<String> = self
+            .sums
+            .iter()
+            .map(|sum| (sum / self.count as f64).to_string())
+            .collect();
+        self.count = 0;
+        Some((
+            self.first.midpoint(self.last),
+            format!("{}\n", means.join(",")),
+        ))
+    }
+}
+
+/// Rewrites reading lines for --normalize: `;` between the fields and every number
+/// formatted the same way.
+struct Normalizer {
+    decimals: Option<usize>,
+    /// Fields per row, from --expected-columns or else the first row.
+    columns: Option<usize>,
+    line: String,
+}
+
+impl Normalizer {
+    fn new(decimals: Option<usize>, columns: Option<usize>) -> Self {
+        Normalizer {
+            decimals,
+            columns,
+            line: String::new(),
+        }
+    }
+
+    fn normalize(&mut self, line: &str, stats: &mut Stats) -> &str {
+        use std::fmt::Write;
+
+        let fields: Vec<&str> = split_reading_fields(line).collect();
+        let columns = *self.columns.get_or_insert(fields.len());
+        if fields.len() != columns {
+            stats.reshaped_rows += 1;
+        }
+
+        self.line.clear();
+        for i in 0..columns {
+            if i > 0 {
+                self.line.push(';');
+            }
+            match fields.get(i).map(|field| field.parse::<f64>()) {
+                Some(Ok(value)) => {
+                    let _ = match self.decimals {
+                        Some(decimals) => write!(self.line, "{:.*}", decimals, value),
+                        None => write!(self.line, "{}", value),
+                    };
+                }
+                Some(Err(_)) => {
+                    stats.non_numeric_fields += 1;
+                    self.line.push_str("NaN");
+                }
+                None => self.line.push_str("NaN"),
+            }
+        }
+        self.line.push('\n');
+        &self.line
+    }
+}
+
+/// The label currently in effect. `window` counts label changes, so every stretch of
+/// time between two labels has its own number.
+#[derive(Clone, Debug)]
+struct CurrentLabel {
+    activity: Activity,
+    window: usize,
+    started_at: u128,
+    /// Readings recorded since the label was set.
+    samples: u64,
+}
+
+/// State shared between the activity thread and the read loop.
+struct SessionState {
+    done: AtomicBool,
+    /// Set with `done` when the participant pressed the --abort-key.
+    participant_abort: AtomicBool,
+    /// Set by the read loop once the warm-up is over and accepted.
+    warmed_up: AtomicBool,
+    label: Mutex<CurrentLabel>,
+    /// Random choices made by the protocol so far, as `kind;value`.
+    choices: Mutex<Vec<String>>,
+    /// Readings recorded in the whole session, for the status line.
+    samples: AtomicU64,
+    /// When the readings of the last RATE_WINDOW_MS of the current window arrived.
+    recent_samples: Mutex<VecDeque<u128>>,
+    /// The activity being run, from 1, and how many there are.
+    activity_index: AtomicUsize,
+    activity_count: AtomicUsize,
+}
+
+impl SessionState {
+    fn new() -> Self {
+        SessionState {
+            done: AtomicBool::new(false),
+            participant_abort: AtomicBool::new(false),
+            warmed_up: AtomicBool::new(false),
+            label: Mutex::new(CurrentLabel {
+                activity: Activity::OTHER,
+                window: 0,
+                started_at: now_ms(),
+                samples: 0,
+            }),
+            choices: Mutex::new(Vec::new()),
+            samples: AtomicU64::new(0),
+            recent_samples: Mutex::new(VecDeque::new()),
+            activity_index: AtomicUsize::new(0),
+            activity_count: AtomicUsize::new(0),
+        }
+    }
+
+    fn label(&self) -> CurrentLabel {
+        self.label
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    fn activity(&self) -> Activity {
+        self.label().activity
+    }
+
+    fn set_activity(&self, activity: &Activity) {
+        let mut label = self.label.lock().unwrap_or_else(PoisonError::into_inner);
+        label.activity = activity.clone();
+        label.window += 1;
+        label.started_at = now_ms();
+        label.samples = 0;
+        self.recent_samples().clear();
+    }
+
+    fn count_sample(&self) {
+        self.label
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .samples += 1;
+        self.samples.fetch_add(1, Ordering::Relaxed);
+        let now = now_ms();
+        let mut recent = self.recent_samples();
+        recent.push_back(now);
+        while recent
+            .front()
+            .is_some_and(|&t| now.saturating_sub(t) > RATE_WINDOW_MS)
+        {
+            recent.pop_front();
+        }
+    }
+
+    fn recent_samples(&self) -> MutexGuard<'_, VecDeque<u128>> {
+        self.recent_samples
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Readings per second over the last RATE_WINDOW_MS of the current window, or
+    /// `None` in its first quarter, when a handful of readings would skew it.
+    fn recent_rate(&self) -> Option<f64> {
+        let now = now_ms();
+        let span = now
+            .saturating_sub(self.label().started_at)
+            .min(RATE_WINDOW_MS);
+        if span < RATE_WINDOW_MS / 4 {
+            return None;
+        }
+        let recent = self.recent_samples();
+        let samples = recent
+            .iter()
+            .filter(|&&t| now.saturating_sub(t) <= span)
+            .count();
+        Some(samples as f64 * 1000.0 / span as f64)
+    }
+
+    /// Blocks until the warm-up is accepted or the session is done, returning whether
+    /// it was accepted.
+    fn wait_for_warmup(&self) -> bool {
+        while !self.warmed_up.load(Ordering::Relaxed) {
+            if self.done.load(Ordering::Relaxed) {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        true
+    }
+
+    /// Blocks until the current window has `count` readings or the session is done.
+    fn wait_for_samples(&self, count: u64) {
+        while self.label().samples < count && !self.done.load(Ordering::Relaxed) {
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    /// Whether a real (non-OTHER) activity is currently being performed.
+    fn is_active(&self) -> bool {
+        !matches!(
+            self.activity(),
+            Activity::OTHER | Activity::PREPARE | Activity::COOLDOWN
+        )
+    }
+}
+
+/// Counters collected while recording, printed as the end-of-session summary.
+#[derive(Default, Debug)]
+struct Stats {
+    samples: usize,
+    duration: Duration,
+    gaps: usize,
+    /// Largest gap between readings as `(starts at, length)` in ms.
+    largest_gap: Option<(u128, u128)>,
+    /// Lines after the warm-up that were dropped for not being UTF-8.
+    invalid_utf8: usize,
+    /// Failed reads other than timeouts.
+    read_errors: usize,
+    /// Samples recorded under each label, by --lang activity name.
+    activity_samples: BTreeMap<&'static str, usize>,
+    /// Lines between flushes that --flush-target-ms settled on.
+    flush_every: Option<usize>,
+    /// NaN rows written by --fill-gaps.
+    filled_rows: usize,
+    /// With --normalize, tokens written as NaN for not being numbers.
+    non_numeric_fields: usize,
+    /// With --normalize, rows padded or cut to the expected width.
+    reshaped_rows: usize,
+    /// Values at the --clip-range bounds, by channel.
+    clipped: Vec<usize>,
+    /// Readings timestamped before the previous one, clamped or flagged.
+    backwards_timestamps: usize,
+    /// Times --reconnect reopened the device and re-ran the warm-up.
+    reconnects: usize,
+}
+
+impl Stats {
+    fn count_sample(&mut self, activity: &Activity) {
+        self.samples += 1;
+        *self
+            .activity_samples
+            .entry(message_key(activity))
+            .or_default() += 1;
+    }
+
+    fn rate(&self) -> Option<f64> {
+        let secs = self.duration.as_secs_f64();
+        (secs > 0.0).then(|| self.samples as f64 / secs)
+    }
+
+    fn record_gap(&mut self, at: u128, length: u128) {
+        self.gaps += 1;
+        if self.largest_gap.is_none_or(|(_, largest)| length > largest) {
+            self.largest_gap = Some((at, length));
+        }
+    }
+}
+
+fn print_summary(stats: &Stats, args: &Args) {
+    let max_gap_ms = args.max_gap_ms;
+    println!("\nSamples:  {}", stats.samples);
+    println!("Duration: {:.1}s", stats.duration.as_secs_f64());
+    if let Some(rate) = stats.rate() {
+        println!("Rate:     {:.1} Hz", rate);
+    }
+    if let Some(max_gap) = max_gap_ms {
+        print!("Gaps over {}ms: {}", max_gap, stats.gaps);
+        if let Some((at, length)) = stats.largest_gap {
+            print!(" (largest {}ms at {})", length, at);
+        }
+        println!();
+    }
+    if stats.invalid_utf8 > 0 {
+        println!("Invalid UTF-8 lines dropped: {}", stats.invalid_utf8);
+    }
+    if stats.read_errors > 0 {
+        println!("Read errors: {}", stats.read_errors);
+    }
+    if stats.reconnects > 0 {
+        println!("Reconnects (each with a new warm-up): {}", stats.reconnects);
+    }
+    if stats.filled_rows > 0 {
+        println!("Rows filled into gaps: {}", stats.filled_rows);
+    }
+    if stats.non_numeric_fields > 0 {
+        println!(
+            "Non-numeric fields written as NaN: {}",
+            stats.non_numeric_fields
+        );
+    }
+    if stats.reshaped_rows > 0 {
+        println!("Rows padded or cut to width: {}", stats.reshaped_rows);
+    }
+    if stats.clipped.iter().any(|&count| count > 0) {
+        let channels: Vec<String> = channel_counts(&stats.clipped)
+            .map(|(channel, count)| format!("{} {}", channel, count))
+            .collect();
+        println!("Clipped values: {}", channels.join(", "));
+    }
+    if let Some(policy) = args.backwards_timestamps
+        && stats.backwards_timestamps > 0
+    {
+        let action = match policy {
+            BackwardsTimestamps::Clamp => "clamped",
+            BackwardsTimestamps::Flag => "flagged",
+        };
+        println!(
+            "Timestamps behind the previous one: {} ({})",
+            stats.backwards_timestamps, action
+        );
+    }
+    if let Some(every) = stats.flush_every {
+        println!("Flushed every {} lines", every);
+    }
+}
+
+/// `(v<n>, count)` for the channels with a nonzero count.
+fn channel_counts(counts: &[usize]) -> impl Iterator<Item = (String, usize)> {
+    counts
+        .iter()
+        .enumerate()
+        .filter(|(_, count)| **count > 0)
+        .map(|(i, count)| (format!("v{}", i + 1), *count))
+}
+
+/// Counts the values at or beyond the --clip-range bounds, per channel. With
+/// --clip-warn-percent, checks the share of clipped readings every second and warns
+/// while it is over the threshold.
+struct ClipDetector {
+    min: f64,
+    max: f64,
+    warn_percent: Option<f64>,
+    window_started_at: Option<u128>,
+    window_readings: usize,
+    window_clipped: usize,
+}
+
+impl ClipDetector {
+    fn new(min: f64, max: f64, warn_percent: Option<f64>) -> Self {
+        ClipDetector {
+            min,
+            max,
+            warn_percent,
+            window_started_at: None,
+            window_readings: 0,
+            window_clipped: 0,
+        }
+    }
+
+    fn check(&mut self, line: &str, now: u128, clipped: &mut Vec<usize>) {
+        let mut any = false;
+        for (i, field) in split_reading_fields(line).enumerate() {
+            let Ok(value) = field.parse::<f64>() else {
+                continue;
+            };
+            if value <= self.min || value >= self.max {
+                if clipped.len() <= i {
+                    clipped.resize(i + 1, 0);
+                }
+                clipped[i] += 1;
+                any = true;
+            }
+        }
+
+        let Some(warn_percent) = self.warn_percent else {
+            return;
+        };
+        let started_at = *self.window_started_at.get_or_insert(now);
+        self.window_readings += 1;
+        self.window_clipped += usize::from(any);
+        if now.saturating_sub(started_at) < CLIP_WARN_WINDOW_MS {
+            return;
+        }
+        let percent = 100.0 * self.window_clipped as f64 / self.window_readings as f64;
+        if percent > warn_percent {
+            eprintln!(
+                "CLIPPING: {:.0}% of the readings in the last second are at the --clip-range bounds",
+                percent
+            );
+        }
+        self.window_started_at = Some(now);
+        self.window_readings = 0;
+        self.window_clipped = 0;
+    }
+}
+
+/// The timestamps of the readings missing between `last` and `now` at `hz`, if the
+/// gap is long enough for at least one to be missing.
+fn missing_timestamps(last: u128, now: u128, hz: f64) -> impl Iterator<Item = u128> {
+    let period = 1000.0 / hz;
+    let missing = ((now.saturating_sub(last) as f64 / period).round() as u64).saturating_sub(1);
+    (1..=missing).map(move |i| last + (i as f64 * period).round() as u128)
+}
+
+/// How many lines go between flushes of the readings. With a target, the count is
+/// halved after a flush slower than the target and grows by a quarter after one that
+/// took less than half of it.
+struct FlushPolicy {
+    every: usize,
+    target: Option<Duration>,
+}
+
+impl FlushPolicy {
+    fn new(target: Option<Duration>) -> Self {
+        FlushPolicy {
+            every: FLUSH_EVERY,
+            target,
+        }
+    }
+
+    fn adapt(&mut self, took: Duration) {
+        let Some(target) = self.target else {
+            return;
+        };
+        if took > target {
+            self.every = (self.every / 2).max(FLUSH_EVERY_MIN);
+        } else if took < target / 2 {
+            self.every = (self.every + self.every / 4).min(FLUSH_EVERY_MAX);
+        }
+    }
+}
+
+/// The same figures as [`print_summary`], as one JSON object.
+fn write_summary_json(
+    path: &Path,
+    stats: &Stats,
+    max_gap_ms: Option<u128>,
+    interrupted: bool,
+) -> io::Result<()> {
+    use std::fmt::Write;
+
+    let mut json = String::new();
+    let _ = write!(json, "{{\"samples\":{}", stats.samples);
+    let _ = write!(
+        json,
+        ",\"duration_secs\":{:.3}",
+        stats.duration.as_secs_f64()
+    );
+    match stats.rate() {
+        Some(rate) => {
+            let _ = write!(json, ",\"rate_hz\":{:.3}", rate);
+        }
+        None => json.push_str(",\"rate_hz\":null"),
+    }
+    if let Some(max_gap) = max_gap_ms {
+        let _ = write!(json, ",\"max_gap_ms\":{},\"gaps\":{}", max_gap, stats.gaps);
+        match stats.largest_gap {
+            Some((at, length)) => {
+                let _ = write!(json, ",\"largest_gap\":{{\"at\":{},\"ms\":{}}}", at, length);
+            }
+            None => json.push_str(",\"largest_gap\":null"),
+        }
+    }
+    let _ = write!(json, ",\"invalid_utf8\":{}", stats.invalid_utf8);
+    let _ = write!(json, ",\"read_errors\":{}", stats.read_errors);
+    let _ = write!(json, ",\"reconnects\":{}", stats.reconnects);
+    let _ = write!(json, ",\"filled_rows\":{}", stats.filled_rows);
+    let _ = write!(
+        json,
+        ",\"backwards_timestamps\":{}",
+        stats.backwards_timestamps
+    );
+    let _ = write!(
+        json,
+        ",\"non_numeric_fields\":{},\"reshaped_rows\":{}",
+        stats.non_numeric_fields, stats.reshaped_rows
+    );
+    json.push_str(",\"clipped\":{");
+    for (i, (channel, count)) in channel_counts(&stats.clipped).enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        let _ = write!(json, "\"{}\":{}", channel, count);
+    }
+    json.push('}');
+    if let Some(every) = stats.flush_every {
+        let _ = write!(json, ",\"flush_every\":{}", every);
+    }
+    json.push_str(",\"activities\":{");
+    for (i, (activity, samples)) in stats.activity_samples.iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        push_json_string(&mut json, activity);
+        let _ = write!(json, ":{}", samples);
+    }
+    let _ = writeln!(json, "}},\"interrupted\":{}}}", interrupted);
+
+    fs::write(path, json)
+}
+
+/// Rewrites labels.csv as labels.json, one interval per label: each label lasts until
+/// the next one, the last until `end`. Annotation rows are left out.
+fn write_labels_json(recording_dir: &Path, end: u128) -> io::Result<()> {
+    use std::fmt::Write;
+
+    let labels = fs::read_to_string(recording_dir.join("labels.csv"))?;
+    let rows: Vec<(u128, Activity, Vec<&str>)> = labels
+        .lines()
+        .filter(|row| !is_label_annotation(row) && *row != LABELS_HEADER)
+        .filter_map(|row| {
+            let (timestamp, activity) = parse_label_row(row)?;
+            Some((timestamp, activity, row.trim().split(';').skip(2).collect()))
+        })
+        .collect();
+
+    let mut json = String::from("[");
+    for (i, (start, activity, extra)) in rows.iter().enumerate() {
+        let until = rows.get(i + 1).map_or(end, |(next, _, _)| *next);
+        if i > 0 {
+            json.push(',');
+        }
+        let _ = write!(json, "\n{{\"start\":{},\"end\":{},\"label\":", start, until);
+        push_json_string(&mut json, message_key(activity));
+        json.push_str(",\"text\":");
+        if extra.is_empty() {
+            json.push_str("null");
+        } else {
+            push_json_string(&mut json, &extra.join(";"));
+        }
+        json.push('}');
+    }
+    json.push_str("\n]\n");
+
+    fs::write(recording_dir.join("labels.json"), json)
+}
+
+fn split_reading_fields(line: &str) -> impl Iterator<Item = &str> {
+    line.split(|c: char| c == ',' || c == ';' || c.is_whitespace())
+        .filter(|field| !field.is_empty())
+}
+
+fn parse_reading_fields(line: &str) -> Option<Vec<f64>> {
+    let fields: Option<Vec<f64>> = split_reading_fields(line)
+        .map(|field| field.parse().ok())
+        .collect();
+    fields.filter(|fields| !fields.is_empty())
+}
+
+/// Whether `line` is numeric, with `expected_columns` fields if given.
+fn is_valid_reading(line: &str, expected_columns: Option<usize>) -> bool {
+    parse_reading_fields(line).is_some_and(|f| expected_columns.is_none_or(|n| f.len() == n))
+}
+
+/// Parses a `readings.csv` row into its timestamp and numeric fields.
+fn parse_reading_row(row: &str, embedded_subject: bool) -> Option<(u128, Vec<f64>)> {
+    let (timestamp, line) = split_reading_row(row, embedded_subject)?;
+    Some((timestamp.parse().ok()?, parse_reading_fields(line)?))
+}
+
+/// Splits a `readings.csv` row into its timestamp and the device's line, leaving out
+/// the subject columns at the end of an --embed-subject recording's rows.
+fn split_reading_row(row: &str, embedded_subject: bool) -> Option<(&str, &str)> {
+    let row = match embedded_subject {
+        true => row.rsplitn(EMBEDDED_SUBJECT_COLUMNS + 1, ';').last()?,
+        false => row,
+    };
+    row.split_once(';')
+}
+
+/// Whether the recording in `dir` was made with --embed-subject.
+fn has_embedded_subject(dir: &Path) -> bool {
+    fs::read_to_string(dir.join("chars.txt"))
+        .is_ok_and(|chars| chars.lines().any(|line| line == "embed_subject=true"))
+}
+
+/// Parses a `labels.csv` row into its timestamp and activity.
+fn parse_label_row(row: &str) -> Option<(u128, Activity)> {
+    let mut fields = row.trim().split(';');
+    let timestamp = fields.next()?.parse().ok()?;
+    let activity = activity_from_label_code(fields.next()?)?;
+    Some((timestamp, activity))
+}
+
+enum WarmupLimit {
+    Lines(usize),
+    Time(Duration),
+    /// `lines` valid lines in a row, or `timeout` after the first line, whichever
+    /// comes first.
+    Stable {
+        lines: usize,
+        timeout: Duration,
+    },
+}
+
+/// Tracks the lines discarded before recording starts.
+struct Warmup {
+    limit: WarmupLimit,
+    started_at: Option<u128>,
+    seen: usize,
+    valid: usize,
+    /// Valid lines since the last invalid one.
+    run: usize,
+    done: bool,
+    /// Times the operator had the warm-up start over after it failed the quality check.
+    retries: usize,
+    /// Set while warming up again after --reconnect reopened the device.
+    after_reconnect: bool,
+}
+
+impl Warmup {
+    fn new(limit: WarmupLimit) -> Self {
+        let done = match limit {
+            WarmupLimit::Lines(lines) => lines == 0,
+            WarmupLimit::Time(duration) => duration.is_zero(),
+            WarmupLimit::Stable { lines, .. } => lines == 0,
+        };
+        Warmup {
+            limit,
+            started_at: None,
+            seen: 0,
+            valid: 0,
+            run: 0,
+            done,
+            retries: 0,
+            after_reconnect: false,
+        }
+    }
+
+    /// Starts over, for another try after a failed quality check.
+    fn restart(&mut self) {
+        self.reset();
+        self.retries += 1;
+    }
+
+    /// Starts over after --reconnect reopened the device, whose fresh stream begins
+    /// with junk again.
+    fn rerun(&mut self) {
+        self.reset();
+        self.after_reconnect = true;
+    }
+
+    fn reset(&mut self) {
+        self.started_at = None;
+        self.seen = 0;
+        self.valid = 0;
+        self.run = 0;
+        self.done = false;
+    }
+
+    /// With a stabilization warm-up, whether it ended because the device settled.
+    fn stabilized(&self) -> Option<bool> {
+        match self.limit {
+            WarmupLimit::Stable { lines, .. } => Some(self.run >= lines),
+            _ => None,
+        }
+    }
+
+    /// Counts one discarded line and returns true once the warm-up is over.
+    fn observe(&mut self, valid: bool) -> bool {
+        let now = now_ms();
+        let started_at = *self.started_at.get_or_insert(now);
+
+        self.seen += 1;
+        if valid {
+            self.valid += 1;
+            self.run += 1;
+        } else {
+            self.run = 0;
+        }
+
+        self.done = match self.limit {
+            WarmupLimit::Lines(lines) => self.seen >= lines,
+            WarmupLimit::Time(duration) => now.saturating_sub(started_at) >= duration.as_millis(),
+            WarmupLimit::Stable { lines, timeout } => {
+                self.run >= lines || now.saturating_sub(started_at) >= timeout.as_millis()
+            }
+        };
+        self.done
+    }
+}
+
+/// Shows where the device resolves to and what it sends, and asks the operator
+/// whether to record from it.
+fn confirm_device(reader: &mut io::BufReader<Box<dyn Source>>, dev: &Path) -> io::Result<()> {
+    match fs::canonicalize(dev) {
+        Ok(resolved) if resolved != dev => {
+            println!("Device: {} -> {}", dev.display(), resolved.display())
+        }
+        _ => println!("Device: {}", dev.display()),
+    }
+
+    let mut banner = Vec::new();
+    let mut first_reading = None;
+    let mut line = String::new();
+    let started = Instant::now();
+    while started.elapsed() < DEVICE_PROBE_DURATION && banner.len() < DEVICE_BANNER_LINES {
+        match reader.read_line(&mut line) {
+            Ok(_) => {
+                let text = line.trim();
+                if parse_reading_fields(text).is_some() {
+                    first_reading.get_or_insert_with(|| text.to_string());
+                } else if !text.is_empty() {
+                    banner.push(text.to_string());
+                }
+                line.clear();
+            }
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => {}
+            Err(_) => line.clear(),
+        }
+    }
+
+    for text in &banner {
+        println!("  {}", text);
+    }
+    match first_reading {
+        Some(reading) => println!("  first reading: {}", reading),
+        None if banner.is_empty() => println!("  no data received yet"),
+        None => {}
+    }
+
+    if prompt_choice("Record from this device? (Y/n): ", &["y", "n"], Some("y"))? == "n" {
+        return Err(io::Error::new(
+            io::ErrorKind::Interrupted,
+            "device not confirmed by the operator",
+        ));
+    }
+    Ok(())
+}
+
+/// Averages the contact-quality field over a short window, asking the operator to
+/// reseat the sensor until it reaches `min` or they override. Returns the last measured
+/// quality and whether the operator overrode the check.
+fn check_contact(
+    reader: &mut io::BufReader<Box<dyn Source>>,
+    field: usize,
+    min: f64,
+) -> io::Result<(f64, bool)> {
+    loop {
+        // skip whatever piled up while the prompts were answered
+        reader.get_mut().discard_input()?;
+        reader.consume(reader.buffer().len());
+
+        let mut sum = 0.0;
+        let mut count = 0;
+        let mut line = String::new();
+        let started = Instant::now();
+        while started.elapsed() < CONTACT_CHECK_DURATION {
+            match reader.read_line(&mut line) {
+                Ok(_) => {
+                    if let Some(value) =
+                        parse_reading_fields(&line).and_then(|f| f.get(field).copied())
+                    {
+                        sum += value;
+                        count += 1;
+                    }
+                    line.clear();
+                }
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => {}
+                Err(_) => line.clear(),
+            }
+        }
+
+        let quality = if count > 0 {
+            sum / count as f64
+        } else {
+            f64::NAN
+        };
+        if quality >= min {
+            println!("Contact quality {:.2} ok", quality);
+            return Ok((quality, false));
+        }
+
+        eprintln!(
+            "Contact quality {:.2} is below {}. Reseat the sensor.",
+            quality, min
+        );
+        let choice = prompt_choice("re-check or override? (R/o): ", &["r", "o"], Some("r"))?;
+        if choice == "o" {
+            return Ok((quality, true));
+        }
+    }
+}
+
+/// Reports how the warm-up ended and checks its quality. When the check fails and the
+/// operator is at a terminal, they decide whether to retry the warm-up, which restarts
+/// `warmup`, record anyway, or abort.
+fn finish_warmup(
+    warmup: &mut Warmup,
+    args: &Args,
+    baud: u32,
+    char_file: &mut File,
+) -> io::Result<()> {
+    if warmup.after_reconnect {
+        // the protocol is already running, so there's no stopping for the quality check
+        let ended = match warmup.stabilized() {
+            Some(true) => "stable",
+            Some(false) => "timeout",
+            None => "done",
+        };
+        println!("Warm-up after reconnecting ended ({})", ended);
+        let _ = writeln!(char_file, "reconnect_warmup={}", ended);
+        warmup.after_reconnect = false;
+        return Ok(());
+    }
+    match warmup.stabilized() {
+        Some(true) => {
+            println!("Warm-up ended: {} valid lines in a row", warmup.run);
+            let _ = writeln!(char_file, "warmup_ended=stable");
+        }
+        Some(false) => {
+            eprintln!(
+                "Warm-up timed out after {:.1}s without settling, recording anyway",
+                args.warmup_timeout_secs.as_secs_f64()
+            );
+            let _ = writeln!(char_file, "warmup_ended=timeout");
+        }
+        None => {}
+    }
+    if warmup.seen > 0 {
+        let _ = writeln!(
+            char_file,
+            "warmup_valid_fraction={:.3}",
+            warmup.valid as f64 / warmup.seen as f64
+        );
+    }
+    let Err(e) = check_warmup_quality(warmup, args.min_valid_fraction, baud, args.expected_columns)
+    else {
+        if warmup.retries > 0 {
+            let _ = writeln!(char_file, "warmup_check=passed");
+        }
+        return Ok(());
+    };
+    if STDIN_SOURCE.load(Ordering::Relaxed) || !io::stdin().is_terminal() {
+        return Err(e);
+    }
+
+    let _ = writeln!(char_file, "warmup_check=failed");
+    eprintln!("\n{}", e);
+    let choice = prompt_choice(
+        "Retry the warm-up, continue anyway or abort? (r/c/A): ",
+        &["r", "c", "a"],
+        Some("a"),
+    )?;
+    match choice.as_str() {
+        "r" => {
+            let _ = writeln!(char_file, "warmup_choice=retry");
+            println!("Retrying the warm-up");
+            warmup.restart();
+            Ok(())
+        }
+        "c" => {
+            let _ = writeln!(char_file, "warmup_choice=continue");
+            Ok(())
+        }
+        _ => {
+            let _ = writeln!(char_file, "warmup_choice=abort");
+            Err(e)
+        }
+    }
+}
+
+fn check_warmup_quality(
+    warmup: &Warmup,
+    min_fraction: f64,
+    baud: u32,
+    expected_columns: Option<usize>,
+) -> io::Result<()> {
+    if warmup.seen == 0 {
+        return Ok(());
+    }
+
+    let fraction = warmup.valid as f64 / warmup.seen as f64;
+    if fraction >= min_fraction {
+        return Ok(());
+    }
+
+    let valid = match expected_columns {
+        Some(n) => format!("numeric with {} columns", n),
+        None => "numeric".to_string(),
+    };
+
+    let others: Vec<String> = COMMON_BAUD_RATES
+        .iter()
+        .filter(|&&rate| rate != baud)
+        .map(|rate| rate.to_string())
+        .collect();
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "Only {:.0}% of warm-up lines were {} at {} baud. \
+             Check the sensor wiring and the baud rate (try --baud with one of: {})",
+            fraction * 100.0,
+            valid,
+            baud,
+            others.join(", ")
+        ),
+    ))
+}
+
+/// Listens at each of COMMON_BAUD_RATES for a moment and leaves the port at the rate
+/// with the highest fraction of numeric lines.
+fn detect_baud(port: &mut SerialPort, expected_columns: Option<usize>) -> io::Result<u32> {
+    let mut best: Option<(u32, f64, usize)> = None;
+    for rate in COMMON_BAUD_RATES {
+        set_baud_rate(port, rate)?;
+        let (seen, valid) = probe_lines(port, expected_columns);
+        println!("  {:>6} baud: {} of {} lines numeric", rate, valid, seen);
+        if valid == 0 {
+            continue;
+        }
+        let fraction = valid as f64 / seen as f64;
+        if best.is_none_or(|(_, best_fraction, best_valid)| {
+            fraction > best_fraction || (fraction == best_fraction && valid > best_valid)
+        }) {
+            best = Some((rate, fraction, valid));
+        }
+    }
+
+    let Some((rate, _, _)) = best else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "no baud rate gave numeric lines, check the sensor wiring",
+        ));
+    };
+    set_baud_rate(port, rate)?;
+    Ok(rate)
+}
+
+fn set_baud_rate(port: &mut SerialPort, rate: u32) -> io::Result<()> {
+    let mut settings = port.get_configuration()?;
+    settings.set_baud_rate(rate)?;
+    port.set_configuration(&settings)?;
+    port.discard_input_buffer()
+}
+
+/// Counts the lines read during BAUD_PROBE_DURATION and how many of them were valid.
+/// The first line is skipped, it likely started before the rate changed.
+fn probe_lines(port: &SerialPort, expected_columns: Option<usize>) -> (usize, usize) {
+    let mut reader = io::BufReader::new(port);
+    let mut raw = Vec::new();
+    let (mut seen, mut valid) = (0, 0);
+    let mut first = true;
+    let started = Instant::now();
+    while started.elapsed() < BAUD_PROBE_DURATION {
+        match reader.read_until(b'\n', &mut raw) {
+            Ok(0) => break,
+            Ok(_) if first => first = false,
+            Ok(_) => {
+                seen += 1;
+                if std::str::from_utf8(&raw)
+                    .is_ok_and(|line| is_valid_reading(line, expected_columns))
+                {
+                    valid += 1;
+                }
+            }
+            // a timeout keeps the partial line for the next read
+            Err(_) => continue,
+        }
+        raw.clear();
+    }
+    (seen, valid)
+}
+
+/// Reads the next device line into `raw`, decoded here rather than by read_line,
+/// which drops invalid UTF-8 without a trace. A line that isn't UTF-8 comes back as
+/// `Err` with the bad bytes replaced, for reporting.
+fn read_device_line<'a>(
+    reader: &mut impl BufRead,
+    raw: &'a mut Vec<u8>,
+) -> io::Result<(usize, Result<&'a str, String>)> {
+    let n = reader.read_until(b'\n', raw)?;
+    let line = std::str::from_utf8(raw).map_err(|_| String::from_utf8_lossy(raw).into_owned());
+    Ok((n, line))
+}
+
+/// Where the readings come from, kept for --reconnect to open the source again.
+enum Origin {
+    Serial {
+        baud: u32,
+        line: SerialLine,
+    },
+    Tcp(String),
+    /// A named pipe, opened again for the next writer.
+    Fifo,
+    /// Standard input can't be reopened once the writer closed it.
+    Stdin,
+}
+
+impl Origin {
+    /// Whether a failed read means the source went away rather than a glitch.
+    fn is_gone(&self, dev: &Path) -> bool {
+        match self {
+            // unplugged once the device node is gone
+            Origin::Serial { .. } => !dev.exists(),
+            Origin::Tcp(_) => true,
+            Origin::Fifo | Origin::Stdin => false,
+        }
+    }
+
+    fn reopen(&self, dev: &Path) -> io::Result<Box<dyn Source>> {
+        match self {
+            Origin::Serial { baud, line } => {
+                let mut port = open_port_exclusive(dev, *baud, line)?;
+                port.set_read_timeout(READ_TIMEOUT)?;
+                Ok(Box::new(port))
+            }
+            Origin::Tcp(address) => Ok(Box::new(TcpSource::connect(address, READ_TIMEOUT)?)),
+            Origin::Fifo => {
+                println!("Waiting for a writer on {}", dev.display());
+                let fifo = PipeSource::reopen_fifo(dev, READ_TIMEOUT, RECONNECT_TIMEOUT)?;
+                Ok(Box::new(fifo))
+            }
+            Origin::Stdin => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "standard input can't be reopened",
+            )),
+        }
+    }
+}
+
+/// --reconnect: reopens the source after it went away, unless that already happened
+/// `max` times, giving the device RECONNECT_TIMEOUT to come back.
+fn reconnect(
+    origin: &Origin,
+    dev: &Path,
+    reconnects: usize,
+    max: usize,
+) -> Option<Box<dyn Source>> {
+    if reconnects >= max {
+        eprintln!(
+            "\x07\nDevice {} went away again after {} reconnects, giving up",
+            dev.display(),
+            reconnects
+        );
+        return None;
+    }
+    eprintln!("\x07\nDevice {} went away, reconnecting", dev.display());
+    let started = Instant::now();
+    loop {
+        match origin.reopen(dev) {
+            Ok(source) => return Some(source),
+            Err(e) if started.elapsed() >= RECONNECT_TIMEOUT => {
+                eprintln!("Could not reopen {}: {}, giving up", dev.display(), e);
+                return None;
+            }
+            Err(_) => thread::sleep(RECONNECT_DELAY),
+        }
+    }
+}
+
+/// Opens the device for exclusive use, offering to pick another port while it is busy.
+/// Returns the port and the path it was opened from.
+fn open_device(
+    mut dev: PathBuf,
+    baud: u32,
+    line: &SerialLine,
+) -> Result<(SerialPort, PathBuf), SessionError> {
+    loop {
+        check_device_path(&dev).map_err(|e| SessionError::new(Failure::Device, e))?;
+        match open_port_exclusive(&dev, baud, line) {
+            Ok(port) => return Ok((port, dev)),
+            Err(e) if e.kind() == io::ErrorKind::ResourceBusy => {
+                eprintln!("Device {} is busy.", dev.display());
+                for (pid, name) in find_port_holders(&dev) {
+                    eprintln!("  held by {} (pid {})", name, pid);
+                }
+                dev = prompt_other_port(&dev).map_err(|e| match e.kind() {
+                    io::ErrorKind::Interrupted => SessionError::from(e),
+                    _ => SessionError::new(Failure::Device, e),
+                })?;
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(SessionError::new(
+                    Failure::Device,
+                    io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("Device not found: {}", dev.display()),
+                    ),
+                ));
+            }
+            Err(e) => return Err(SessionError::new(Failure::Device, e)),
+        }
+    }
+}
+
+/// Parity, stop bits and flow control of the serial port. The default is 8N1 without
+/// flow control, as the sensors ship.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct SerialLine {
+    parity: Parity,
+    stop_bits: u8,
+    flow_control: FlowControl,
+}
+
+impl Default for SerialLine {
+    fn default() -> Self {
+        SerialLine {
+            parity: Parity::None,
+            stop_bits: 1,
+            flow_control: FlowControl::None,
+        }
+    }
+}
+
+impl SerialLine {
+    fn from_args(args: &Args) -> Self {
+        SerialLine {
+            parity: args.parity,
+            stop_bits: args.stop_bits,
+            flow_control: args.flow_control,
+        }
+    }
+
+    fn stop_bits(&self) -> serial2::StopBits {
+        match self.stop_bits {
+            2 => serial2::StopBits::Two,
+            _ => serial2::StopBits::One,
+        }
+    }
+
+    fn apply(&self, settings: &mut serial2::Settings) {
+        settings.set_char_size(serial2::CharSize::Bits8);
+        settings.set_parity(self.parity.to_serial());
+        settings.set_stop_bits(self.stop_bits());
+        settings.set_flow_control(self.flow_control.to_serial());
+    }
+
+    /// The `8N1`-style shorthand.
+    fn frame(&self) -> String {
+        format!("8{}{}", self.parity.letter(), self.stop_bits)
+    }
+}
+
+/// Rejects an existing path that can't be a serial port, e.g. a regular file given by
+/// mistake. Missing paths are left for the open to report.
+fn check_device_path(dev: &Path) -> io::Result<()> {
+    let Ok(metadata) = fs::metadata(dev) else {
+        return Ok(());
+    };
+
+    #[cfg(unix)]
+    let is_device = {
+        use std::os::unix::fs::FileTypeExt;
+        metadata.file_type().is_char_device()
+    };
+    #[cfg(not(unix))]
+    let is_device = !metadata.is_file() && !metadata.is_dir();
+
+    if is_device {
+        return Ok(());
+    }
+    let kind = if metadata.is_dir() {
+        "a directory"
+    } else if metadata.is_file() {
+        "a regular file"
+    } else {
+        "a special file other than a character device"
+    };
+    Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!(
+            "'{}' is {}, not a serial device (expected something like /dev/ttyUSB0)",
+            dev.display(),
+            kind
+        ),
+    ))
+}
+
+fn open_port_exclusive(dev: &Path, baud: u32, line: &SerialLine) -> io::Result<SerialPort> {
+    let port = SerialPort::open(dev, |mut settings: serial2::Settings| {
+        settings.set_raw();
+        settings.set_baud_rate(baud)?;
+        line.apply(&mut settings);
+        Ok(settings)
+    })
+    .map_err(|e| match e.kind() {
+        // serial2 reads the settings back and fails when the driver dropped some
+        io::ErrorKind::Other if *line != SerialLine::default() => io::Error::other(format!(
+            "{} ({}, flow control {})",
+            e,
+            line.frame(),
+            line.flow_control.to_possible_value().unwrap().get_name()
+        )),
+        _ => e,
+    })?;
+
+    // TIOCEXCL makes any later open() of the tty fail with EBUSY instead of
+    // silently sharing (and splitting) the stream with a second reader
+    #[cfg(unix)]
+    {
+        use std::os::fd::AsRawFd;
+        if unsafe { libc::ioctl(port.as_raw_fd(), libc::TIOCEXCL) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    Ok(port)
+}
+
+/// Lists processes that have `dev` open, as `(pid, command name)`. Empty where /proc
+/// is unavailable or the holders belong to another user.
+fn find_port_holders(dev: &Path) -> Vec<(u32, String)> {
+    let Ok(target) = fs::canonicalize(dev) else {
+        return Vec::new();
+    };
+    let Ok(procs) = fs::read_dir("/proc") else {
+        return Vec::new();
+    };
+
+    let mut holders = Vec::new();
+    for proc_entry in procs.flatten() {
+        let Some(pid) = proc_entry.file_name().to_str().and_then(|s| s.parse().ok()) else {
+            continue;
+        };
+        let Ok(fds) = fs::read_dir(proc_entry.path().join("fd")) else {
+            continue;
+        };
+        let holds_dev = fds
+            .flatten()
+            .any(|fd| fs::read_link(fd.path()).is_ok_and(|link| link == target));
+        if holds_dev {
+            let name = fs::read_to_string(proc_entry.path().join("comm"))
+                .map(|comm| comm.trim().to_string())
+                .unwrap_or_else(|_| "unknown".to_string());
+            holders.push((pid, name));
+        }
+    }
+    holders
+}
+
+fn prompt_other_port(busy: &Path) -> io::Result<PathBuf> {
+    let ports: Vec<PathBuf> = SerialPort::available_ports()?
+        .into_iter()
+        .filter(|port| port != busy)
+        .collect();
+    if ports.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::ResourceBusy,
+            format!(
+                "Device {} is busy and no other ports are available",
+                busy.display()
+            ),
+        ));
+    }
+
+    for (i, port) in ports.iter().enumerate() {
+        println!("  {}) {}", i + 1, port.display());
+    }
+    let numbers: Vec<String> = (1..=ports.len()).map(|i| i.to_string()).collect();
+    let mut allowed: Vec<&str> = numbers.iter().map(String::as_str).collect();
+    allowed.push("q");
+
+    let choice = prompt_choice("pick another port (number, q to quit): ", &allowed, None)?;
+    match choice.parse::<usize>() {
+        Ok(i) => Ok(ports[i - 1].clone()),
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::Interrupted,
+            format!("Device {} is busy", busy.display()),
+        )),
+    }
+}
+
+fn validate_dir(dir: &Path) -> io::Result<()> {
+    if !dir.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "'{}' does not exist",
+                dir.to_str().unwrap_or("Failed to convert to string")
+            ),
+        ));
+    }
+    if !dir.is_dir() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "'{}' is not a directory",
+                dir.to_str().unwrap_or("Failed to convert to string")
+            ),
+        ));
+    }
+    Ok(())
+}
+
+fn parse_activity_code(s: &str) -> Result<Activity, String> {
+    activity_from_label_code(s.trim())
+        .filter(|activity| {
+            !matches!(
+                activity,
+                Activity::PREPARE | Activity::BASELINE | Activity::COOLDOWN
+            )
+        })
+        .ok_or_else(|| {
+            format!(
+                "'{}' is not an activity code (expected one of t, e, s, f, n, c, o or their names)",
+                s
+            )
+        })
+}
+
+fn parse_beep_pattern(s: &str) -> Result<BeepPattern, String> {
+    let (code, pattern) = s.split_once('=').ok_or_else(|| {
+        format!(
+            "'{}' is not a beep pattern (expected e.g. t=2 or t=2@150)",
+            s
+        )
+    })?;
+    let activity = parse_activity_code(code)?;
+    let (count, gap_ms) = match pattern.split_once('@') {
+        Some((count, gap_ms)) => (count, Some(gap_ms)),
+        None => (pattern, None),
+    };
+    let count = count
+        .trim()
+        .parse()
+        .map_err(|_| format!("'{}' is not a number of beeps", count))?;
+    let gap = match gap_ms {
+        Some(ms) => Duration::from_millis(
+            ms.trim()
+                .parse()
+                .map_err(|_| format!("'{}' is not a number of milliseconds", ms))?,
+        ),
+        None => BEEP_GAP,
+    };
+    Ok(BeepPattern {
+        activity,
+        count,
+        gap,
+    })
+}
+
+fn parse_label_code(s: &str) -> Result<Activity, String> {
+    activity_from_label_code(s.trim()).ok_or_else(|| format!("'{}' is not a label code", s))
+}
+
+/// Rejects activity lists that would make the session pointless.
+fn check_activities(activities: &[Activity], min_count: usize) -> io::Result<()> {
+    if activities.len() < min_count {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "the session has {} activities, at least {} required",
+                activities.len(),
+                min_count
+            ),
+        ));
+    }
+    if activities
+        .iter()
+        .all(|activity| matches!(activity, Activity::OTHER))
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the session has no activities other than 'o'",
+        ));
+    }
+    Ok(())
+}
+
+fn parse_env_tag(s: &str) -> Result<(String, String), String> {
+    let (key, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("'{}' is not in KEY=VALUE form", s))?;
+
+    let mut chars = key.chars();
+    let valid_key = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid_key {
+        return Err(format!(
+            "'{}' is not a valid key (expected letters, digits and '_', not starting with a digit)",
+            key
+        ));
+    }
+    if value.contains(['\n', '\r']) {
+        return Err(format!("value for '{}' must be a single line", key));
+    }
+
+    Ok((key.to_string(), value.to_string()))
+}
+
+fn parse_positive(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(speed) if speed > 0.0 && speed.is_finite() => Ok(speed),
+        _ => Err(format!("'{}' is not a positive number", s)),
+    }
+}
+
+fn parse_clip_range(s: &str) -> Result<(f64, f64), String> {
+    let (min, max) = s
+        .split_once(':')
+        .ok_or_else(|| format!("'{}' is not a range (expected e.g. -2:2)", s))?;
+    let parse = |bound: &str| {
+        bound
+            .trim()
+            .parse::<f64>()
+            .ok()
+            .filter(|bound| bound.is_finite())
+            .ok_or_else(|| format!("'{}' is not a number", bound))
+    };
+    let (min, max) = (parse(min)?, parse(max)?);
+    if min >= max {
+        return Err(format!("'{}' is an empty range", s));
+    }
+    Ok((min, max))
+}
+
+fn parse_secs(s: &str) -> Result<Duration, String> {
+    let secs: f64 = s.parse().map_err(|_| format!("'{}' is not a number", s))?;
+    Duration::try_from_secs_f64(secs).map_err(|_| format!("'{}' is not a valid duration", s))
+}
+
+fn parse_dir_template(s: &str) -> Result<String, String> {
+    let path = Path::new(s);
+    let relative = path
+        .components()
+        .all(|component| matches!(component, std::path::Component::Normal(_)));
+    if s.is_empty() || !relative {
+        return Err(format!("'{}' must be a relative path without '..'", s));
+    }
+    Ok(s.to_string())
+}
+
+/// Expands %Y, %m, %d, %H, %M, %S and %% in `template` using the local time at
+/// `timestamp_ms`.
+fn format_local_time(template: &str, timestamp_ms: u128) -> String {
+    let tm = local_time((timestamp_ms / 1000) as i64);
+
+    let mut out = String::new();
+    let mut chars = template.chars();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('Y') => out.push_str(&format!("{:04}", tm.year)),
+            Some('m') => out.push_str(&format!("{:02}", tm.month)),
+            Some('d') => out.push_str(&format!("{:02}", tm.day)),
+            Some('H') => out.push_str(&format!("{:02}", tm.hour)),
+            Some('M') => out.push_str(&format!("{:02}", tm.minute)),
+            Some('S') => out.push_str(&format!("{:02}", tm.second)),
+            Some('%') => out.push('%'),
+            Some(other) => {
+                out.push('%');
+                out.push(other);
+            }
+            None => out.push('%'),
+        }
+    }
+    out
+}
+
+/// A calendar date and time of day, months and days from 1.
+struct CivilTime {
+    year: i32,
+    month: u32,
+    day: u32,
+    hour: u32,
+    minute: u32,
+    second: u32,
+}
+
+/// `secs` since the Unix epoch in local time.
+#[cfg(unix)]
+fn local_time(secs: i64) -> CivilTime {
+    let secs = secs as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    unsafe { libc::localtime_r(&secs, &mut tm) };
+    CivilTime {
+        year: tm.tm_year + 1900,
+        month: (tm.tm_mon + 1) as u32,
+        day: tm.tm_mday as u32,
+        hour: tm.tm_hour as u32,
+        minute: tm.tm_min as u32,
+        second: tm.tm_sec as u32,
+    }
+}
+
+/// `secs` since the Unix epoch in UTC, without libc's time zone lookup.
+#[cfg(not(unix))]
+fn local_time(secs: i64) -> CivilTime {
+    // days to civil date, as in Howard Hinnant's chrono algorithms
+    let z = secs.div_euclid(86400) + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let of_day = secs.rem_euclid(86400);
+    CivilTime {
+        year: (yoe + era * 400 + i64::from(month <= 2)) as i32,
+        month: month as u32,
+        day: (doy - (153 * mp + 2) / 5 + 1) as u32,
+        hour: (of_day / 3600) as u32,
+        minute: (of_day / 60 % 60) as u32,
+        second: (of_day % 60) as u32,
+    }
+}
+
+fn parse_identifier(s: &str) -> Result<String, String> {
+    if s.is_empty()
+        || !s
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(format!(
+            "'{}' may only contain letters, digits, '-' and '_'",
+            s
+        ));
+    }
+    Ok(s.to_string())
+}
+
+/// Reads the allowed operator ids, one per line, skipping blanks and `#` comments.
+fn read_operators_file(path: &Path) -> io::Result<Vec<String>> {
+    let contents = fs::read_to_string(path)?;
+    let mut operators = Vec::new();
+    for line in contents.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let id = parse_identifier(line).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", path.display(), e),
+            )
+        })?;
+        operators.push(id);
+    }
+    if operators.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} lists no operators", path.display()),
+        ));
+    }
+    Ok(operators)
+}
+
+fn default_salt_file() -> PathBuf {
+    std::env::var_os("HOME")
+        .map(PathBuf::from)
+        .unwrap_or_default()
+        .join(DEFAULT_SALT_FILE_NAME)
+}
+
+/// Reads the salt from `path`, or generates a random 32-byte hex salt and saves it there
+/// so later sessions of the same participant hash to the same value.
+fn load_or_create_salt(path: &Path) -> io::Result<String> {
+    match fs::read_to_string(path) {
+        Ok(salt) => Ok(salt.trim().to_string()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let salt: String = (0..32)
+                .map(|_| format!("{:02x}", rand::random::<u8>()))
+                .collect();
+            fs::write(path, format!("{}\n", salt))?;
+            println!("Created new salt file: {}", path.display());
+            Ok(salt)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Pseudonymizes a participant id as hex(SHA-256(salt + id)), where `salt` is the
+/// trimmed contents of the salt file.
+fn hash_participant_id(id: &str, salt: &str) -> String {
+    sha256::hex_digest(format!("{}{}", salt, id).as_bytes())
+}
+
+/// Returns the next free `<n>` (or `<n>_<suffix>`) recording dir under `base_dir`.
+fn next_numeric_subdir(base_dir: &Path, suffix: Option<&str>) -> io::Result<PathBuf> {
+    let mut current_index = 1;
+
+    let entries = fs::read_dir(base_dir)?;
+    for entry in entries {
+        match entry {
+            Ok(entry) => {
+                // only numbered recording dirs (or their zips) count, so index.csv and
+                // friends don't shift it
+                let path = entry.path();
+                let index = entry.file_name().to_str().and_then(|name| {
+                    name.split(['_', '.'])
+                        .next()
+                        .and_then(|number| number.parse::<u32>().ok())
+                });
+                if let Some(index) = index
+                    && (path.is_dir() || path.extension().is_some_and(|ext| ext == "zip"))
+                {
+                    current_index = current_index.max(index + 1);
+                }
+            }
+            Err(e) => eprintln!("Error reading entry: {}", e),
+        }
+    }
+
+    let name = match suffix {
+        Some(suffix) => format!("{}_{}", current_index, suffix),
+        None => current_index.to_string(),
+    };
+    Ok(base_dir.join(name))
+}
+
+/// Appends a row to the base directory's index, replacing the file in one rename so
+/// readers never see a half-written index.
+fn append_to_index(base_dir: &Path, row: &str) -> io::Result<()> {
+    let index_path = base_dir.join(INDEX_FILE_NAME);
+    let mut contents = match fs::read_to_string(&index_path) {
+        Ok(contents) => upgrade_index(contents),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => format!("{}\n", INDEX_HEADER),
+        Err(e) => return Err(e),
+    };
+    contents.push_str(row);
+    contents.push('\n');
+
+    let tmp_path = base_dir.join(format!("{}.tmp", INDEX_FILE_NAME));
+    let mut tmp_file = File::create(&tmp_path)?;
+    tmp_file.write_all(contents.as_bytes())?;
+    tmp_file.sync_all()?;
+    fs::rename(tmp_path, index_path)
+}
+
+/// Brings an index written by an older version, with fewer columns, up to
+/// INDEX_HEADER by padding its rows with empty fields.
+fn upgrade_index(contents: String) -> String {
+    let header = contents.lines().next().unwrap_or_default();
+    if header == INDEX_HEADER || !INDEX_HEADER.starts_with(header) {
+        return contents;
+    }
+
+    let missing = INDEX_HEADER.split(';').count() - header.split(';').count();
+    let mut upgraded = format!("{}\n", INDEX_HEADER);
+    for row in contents.lines().skip(1) {
+        upgraded.push_str(row);
+        upgraded.push_str(&";".repeat(missing));
+        upgraded.push('\n');
+    }
+    upgraded
+}
+
+fn now_ms() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_millis()
+}
+
+fn now_us() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_micros()
+}
+
+/// Answers to the subject questionnaire.
+#[derive(Clone, Debug)]
+struct Subject {
+    sex: String,
+    hand: String,
+    height: Option<String>,
+}
+
+/// The options and subject of the most recent session in the base directory, for
+/// --repeat-last.
+struct LastSession {
+    args: Args,
+    argv: Vec<String>,
+    subject: Option<Subject>,
+}
+
+impl LastSession {
+    fn load(args: &Args) -> Result<Self, SessionError> {
+        let base_dir = args
+            .dir
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_DIR));
+        let index = fs::read_to_string(base_dir.join(INDEX_FILE_NAME)).unwrap_or_default();
+        let dir = index
+            .lines()
+            .skip(1)
+            .filter_map(|row| row.split(';').next())
+            .filter(|dir| !dir.is_empty())
+            .last()
+            .map(|dir| base_dir.join(dir))
+            .ok_or_else(|| {
+                invalid_input(format!(
+                    "no previous session in {} to repeat",
+                    base_dir.join(INDEX_FILE_NAME).display()
+                ))
+            })?;
+
+        let chars = fs::read_to_string(dir.join("chars.txt"))?;
+        let mut argv = Vec::new();
+        let mut recorded_args = false;
+        let mut identifiers = Vec::new();
+        let mut hashed_id = false;
+        let mut sex = None;
+        let mut hand = None;
+        let mut height = None;
+        for (key, value) in chars.lines().filter_map(|line| line.split_once('=')) {
+            match key {
+                "arg_count" => recorded_args = true,
+                "arg" => argv.push(value.to_string()),
+                "sex" => sex = Some(value.to_string()),
+                "hand" => hand = Some(value.to_string()),
+                "height" => height = Some(value.to_string()),
+                "participant_hash" => hashed_id = true,
+                _ => {
+                    if let Some((option, _)) = IDENTIFYING_OPTIONS.iter().find(|(_, k)| *k == key) {
+                        identifiers.push((option.to_string(), value.to_string()));
+                    }
+                }
+            }
+        }
+        if !recorded_args {
+            return Err(invalid_input(format!(
+                "'{}' was recorded without its options, so it can't be repeated",
+                dir.display()
+            )));
+        }
+        if hashed_id && std::env::var_os("FIDGETSENSE_PARTICIPANT_ID").is_none() {
+            return Err(invalid_input(format!(
+                "'{}' stores only the hash of its participant id; \
+                 give the id again with FIDGETSENSE_PARTICIPANT_ID to repeat it",
+                dir.display()
+            )));
+        }
+        // older recordings kept the identifying options among the args
+        let mut argv = redact_identifiers(&argv);
+        for (option, value) in identifiers {
+            argv.extend([option, value]);
+        }
+
+        let current: Vec<String> = std::env::args_os()
+            .skip(1)
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect();
+        let argv = override_options(&argv, &current);
+
+        let program = std::env::args().next().unwrap_or_default();
+        let args = Args::try_parse_from(std::iter::once(program).chain(argv.iter().cloned()))
+            .map_err(|e| {
+                invalid_input(format!(
+                    "options of '{}' no longer parse: {}",
+                    dir.display(),
+                    e.render().to_string().trim_end()
+                ))
+            })?;
+        println!("Repeating {}: {}", dir.display(), argv.join(" "));
+
+        let subject = match (sex, hand) {
+            (Some(sex), Some(hand)) => Some(Subject { sex, hand, height }),
+            _ => None,
+        };
+        Ok(LastSession {
+            args,
+            argv,
+            subject,
+        })
+    }
+}
+
+/// Options whose values identify a person, with their chars.txt keys. They are left out
+/// of the `arg=` lines, and --repeat-last takes them from the keys instead.
+const IDENTIFYING_OPTIONS: [(&str, &str); 2] = [
+    ("--participant-id", "participant_id"),
+    ("--operator", "operator"),
+];
+
+/// `argv` without the IDENTIFYING_OPTIONS and their values.
+fn redact_identifiers(argv: &[String]) -> Vec<String> {
+    let mut redacted = Vec::new();
+    let mut args = argv.iter();
+    while let Some(arg) = args.next() {
+        let option = arg
+            .split_once('=')
+            .map_or(arg.as_str(), |(option, _)| option);
+        if !IDENTIFYING_OPTIONS.iter().any(|(name, _)| *name == option) {
+            redacted.push(arg.clone());
+        } else if !arg.contains('=') {
+            args.next();
+        }
+    }
+    redacted
+}
+
+/// `replayed` with the options given on the `current` command line taken from there
+/// instead, so that e.g. `--repeat-last --dir X` writes to X. --repeat-last itself is
+/// not carried over.
+fn override_options(replayed: &[String], current: &[String]) -> Vec<String> {
+    let command = Args::command();
+    let Ok(matches) = command
+        .clone()
+        .try_get_matches_from(std::iter::once(String::new()).chain(current.iter().cloned()))
+    else {
+        return replayed.to_vec();
+    };
+
+    let mut given = Vec::new();
+    for arg in command.get_arguments() {
+        let id = arg.get_id().as_str();
+        if matches.value_source(id) != Some(ValueSource::CommandLine) {
+            continue;
+        }
+        let takes_value = arg.get_action().takes_values();
+        if let Some(long) = arg.get_long() {
+            given.push((format!("--{long}"), takes_value));
+        }
+        if let Some(short) = arg.get_short() {
+            given.push((format!("-{short}"), takes_value));
+        }
+    }
+
+    let is_given = |arg: &str| {
+        given.iter().find_map(|(name, takes_value)| {
+            let attached = arg.strip_prefix(name.as_str())?;
+            if attached.is_empty() {
+                Some(*takes_value)
+            } else if attached.starts_with('=') || (!name.starts_with("--") && *takes_value) {
+                Some(false)
+            } else {
+                None
+            }
+        })
+    };
+    let mut argv = Vec::new();
+    let mut args = replayed.iter();
+    while let Some(arg) = args.next() {
+        match is_given(arg) {
+            Some(true) => {
+                args.next();
+            }
+            Some(false) => {}
+            None => argv.push(arg.clone()),
+        }
+    }
+    argv.extend(
+        current
+            .iter()
+            .filter(|arg| *arg != "--repeat-last")
+            .cloned(),
+    );
+    argv
+}
+
+fn invalid_input(message: String) -> SessionError {
+    SessionError::new(
+        Failure::Validation,
+        io::Error::new(io::ErrorKind::InvalidInput, message),
+    )
+}
+
+fn confirm_last_subject(subject: &Subject) -> io::Result<bool> {
+    let prompt = format!(
+        "Same subject as last time (sex={}, hand={}, height={})? (Y/n): ",
+        subject.sex,
+        subject.hand,
+        subject.height.as_deref().unwrap_or("-")
+    );
+    Ok(prompt_choice(&prompt, &["y", "n"], Some("y"))? == "y")
+}
+
+/// Writes the subject's answers as `key=value` lines. Optional fields that were left
+/// unanswered are omitted entirely, so a present key always carries a real value.
+fn write_subject(file: &mut File, subject: &Subject) -> io::Result<()> {
+    writeln!(file, "sex={}", subject.sex)?;
+    writeln!(file, "hand={}", subject.hand)?;
+    write_optional_field(file, "height", subject.height.as_deref())
+}
+
+fn write_optional_field(file: &mut File, key: &str, value: Option<&str>) -> io::Result<()> {
+    match value {
+        Some(value) => writeln!(file, "{}={}", key, value),
+        None => Ok(()),
+    }
+}
+
+fn prompt_subject() -> io::Result<Subject> {
+    let mut subject = Subject {
+        sex: prompt_sex()?,
+        hand: prompt_hand()?,
+        height: prompt_height("height (in cm): ")?,
+    };
+
+    loop {
+        println!(
+            "sex={} hand={} height={}",
+            subject.sex,
+            subject.hand,
+            subject.height.as_deref().unwrap_or("-")
+        );
+        match prompt_choice(
+            "confirm attributes? (Y/n/edit): ",
+            &["y", "n", "edit"],
+            Some("y"),
+        )?
+        .as_str()
+        {
+            "y" => return Ok(subject),
+            "n" => {
+                subject.sex = prompt_sex()?;
+                subject.hand = prompt_hand()?;
+                subject.height = prompt_height("height (in cm): ")?;
+            }
+            _ => match prompt_choice(
+                "field (sex/hand/height): ",
+                &["sex", "hand", "height"],
+                None,
+            )?
+            .as_str()
+            {
+                "sex" => subject.sex = prompt_sex()?,
+                "hand" => subject.hand = prompt_hand()?,
+                _ => subject.height = prompt_height("height (in cm): ")?,
+            },
+        }
+    }
+}
+
+fn prompt_sex() -> io::Result<String> {
+    prompt_choice("sex (f/m): ", &["f", "m"], None)
+}
+
+/// Asks the operator what the participant should do for a --describe-other activity.
+/// `;` would split the label row, so it is replaced.
+fn prompt_other_description(out: &mut Stdout) -> io::Result<String> {
+    if !safe_mode() {
+        execute!(
+            out,
+            terminal::Clear(ClearType::All),
+            cursor::MoveTo(0, 0),
+            cursor::Show
+        )?;
+    }
+    let mut input = String::new();
+    loop {
+        print!("describe the activity: ");
+        out.flush()?;
+        input.clear();
+        read_stdin_line(&mut input)?;
+        let description = input.trim().replace(';', ",");
+        if !description.is_empty() {
+            return Ok(description);
+        }
+    }
+}
+
+/// Asks where the sensor is worn. Returns the placement, and for "other" the
+/// operator's description of it.
+fn prompt_placement(placements: &[String]) -> io::Result<(String, Option<String>)> {
+    let mut choices: Vec<&str> = placements.iter().map(String::as_str).collect();
+    if !choices.contains(&"other") {
+        choices.push("other");
+    }
+    let placement = prompt_choice(
+        &format!("sensor placement ({}): ", choices.join("/")),
+        &choices,
+        None,
+    )?;
+    if placement != "other" {
+        return Ok((placement, None));
+    }
+
+    let mut input = String::new();
+    loop {
+        print!("describe the placement: ");
+        io::stdout().flush()?;
+        input.clear();
+        read_stdin_line(&mut input)?;
+        let description = input.trim();
+        if !description.is_empty() {
+            return Ok((placement, Some(description.to_string())));
+        }
+    }
+}
+
+fn prompt_hand() -> io::Result<String> {
+    prompt_choice("hand (l/R): ", &["l", "r"], Some("r"))
+}
+
+fn read_stdin_line(input: &mut String) -> io::Result<()> {
+    if STDIN_SOURCE.load(Ordering::Relaxed) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "stdin carries the readings (--dev -), so this prompt can't be answered; \
+             pass the answer as an option instead (e.g. --no-subject)",
+        ));
+    }
+    if io::stdin().read_line(input)? == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::Interrupted,
+            "input closed before the prompts were answered",
+        ));
+    }
+    Ok(())
+}
+
+fn prompt_choice(prompt: &str, allowed: &[&str], default_opt: Option<&str>) -> io::Result<String> {
+    let mut input = String::new();
+    loop {
+        print!("{}", prompt);
+        io::stdout().flush()?;
+        input.clear();
+        read_stdin_line(&mut input)?;
+        let mut s = input.trim().to_lowercase();
+        if s.is_empty()
+            && let Some(d) = default_opt
+        {
+            s = d.to_string();
+        }
+        if allowed.contains(&s.as_str()) {
+            return Ok(s);
+        } else {
+            eprintln!("Invalid input. Expected one of {:?}. Try again.", allowed);
+            io::stderr().flush()?;
+        }
+    }
+}
+
+fn prompt_height(prompt: &str) -> io::Result<Option<String>> {
+    let mut input = String::new();
+    loop {
+        print!("{}", prompt);
+        io::stdout().flush()?;
+        input.clear();
+        read_stdin_line(&mut input)?;
+        let s = input.trim();
+        if s.is_empty() {
+            return Ok(None);
+        }
+        match s.parse::<i32>() {
+            Ok(h) if (50..=300).contains(&h) => return Ok(Some(h.to_string())),
+            Ok(_) => {
+                eprintln!("You sure? Height must be between 50 and 300cm. Try again: ");
+                io::stderr().flush()?;
+            }
+            Err(_) => {
+                eprintln!("Height must be a valid integer. Try again: ");
+                io::stderr().flush()?;
+            }
+        }
+    }
+}
+
+/// Lists the activities and asks the operator for the order to run them in.
+fn prompt_activity_order(activities: &[Activity]) -> io::Result<Vec<Activity>> {
+    let mut activities = activities.to_vec();
+    let mut input = String::new();
+    loop {
+        println!("Activity order:");
+        for (i, activity) in activities.iter().enumerate() {
+            println!("  {}. {:?}", i + 1, activity);
+        }
+        print!("New order as positions (e.g. 2,1,3), Enter to keep: ");
+        io::stdout().flush()?;
+        input.clear();
+        read_stdin_line(&mut input)?;
+        let s = input.trim();
+        if s.is_empty() {
+            return Ok(activities);
+        }
+
+        let positions: Option<Vec<usize>> = s
+            .split(',')
+            .map(|p| p.trim().parse::<usize>().ok())
+            .collect();
+        let is_permutation = positions.as_ref().is_some_and(|positions| {
+            let mut sorted = positions.clone();
+            sorted.sort_unstable();
+            sorted.into_iter().eq(1..=activities.len())
+        });
+        match positions {
+            Some(positions) if is_permutation => {
+                activities = positions
+                    .iter()
+                    .map(|&p| activities[p - 1].clone())
+                    .collect();
+            }
+            _ => {
+                eprintln!(
+                    "Expected each position from 1 to {} exactly once. Try again.",
+                    activities.len()
+                );
+                io::stderr().flush()?;
+            }
+        }
+    }
+}
+
+/// The protocol's single source of randomness, seeded with the session's seed. The
+/// choices drawn from it are noted in the session state, so chars.txt can record
+/// them.
+struct SessionRng<'a> {
+    rng: StdRng,
+    state: &'a SessionState,
+}
+
+impl<'a> SessionRng<'a> {
+    fn new(seed: u64, state: &'a SessionState) -> Self {
+        SessionRng {
+            rng: StdRng::seed_from_u64(seed),
+            state,
+        }
+    }
+
+    fn note(&self, kind: &str, value: impl std::fmt::Display) {
+        self.state
+            .choices
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(format!("{};{}", kind, value));
+    }
+}
+
+impl RngCore for SessionRng<'_> {
+    fn next_u32(&mut self) -> u32 {
+        self.rng.next_u32()
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.rng.next_u64()
+    }
+
+    fn fill_bytes(&mut self, dst: &mut [u8]) {
+        self.rng.fill_bytes(dst)
+    }
+}
+
+/// `base` shifted by a uniformly drawn offset in `-jitter_ms..=jitter_ms`.
+fn jittered_duration(base: Duration, jitter_ms: u64, rng: &mut SessionRng) -> Duration {
+    let jitter_ms = jitter_ms as i64;
+    let offset = rng.random_range(-jitter_ms..=jitter_ms);
+    let ms = (base.as_millis() as i64 + offset).max(0);
+    Duration::from_millis(ms as u64)
+}
+
+/// What the participant is taken through, as configured on the command line.
+struct Protocol {
+    activities: Vec<Activity>,
+    order: OrderPolicy,
+    seed: u64,
+    calibrate: bool,
+    randomize_hand: bool,
+    no_repeat_texts: bool,
+    describe_other: bool,
+    start_delay: Duration,
+    tail: Duration,
+    samples_per_activity: Option<u64>,
+    self_paced: bool,
+    abort_key: bool,
+    boundary_key: bool,
+    duration_jitter_ms: Option<u64>,
+    countdown_jitter_ms: Option<u64>,
+    /// Instruction, duration and label of the --baseline segment.
+    baseline: Option<(String, Duration, Activity)>,
+    /// Duration and label of the --cooldown after each activity.
+    cooldown: Option<(Duration, Activity)>,
+    /// Label for the countdown before each activity.
+    lead_in: Activity,
+    /// Bells rung at the start of each activity, empty without --beeps.
+    beeps: Vec<BeepPattern>,
+    /// Every wait is multiplied by this, below 1 for --rehearse-countdown.
+    time_scale: f64,
+}
+
+impl Protocol {
+    fn new(args: &Args, activities: Vec<Activity>, order: OrderPolicy, seed: u64) -> Self {
+        Protocol {
+            activities,
+            order,
+            seed,
+            calibrate: args.calibrate,
+            randomize_hand: args.randomize_hand,
+            no_repeat_texts: args.no_repeat_texts,
+            describe_other: args.describe_other,
+            start_delay: Duration::from_secs(args.start_delay),
+            tail: Duration::from_secs(args.tail_seconds),
+            samples_per_activity: args.samples_per_activity,
+            self_paced: args.self_paced,
+            abort_key: args.abort_key && !safe_mode(),
+            boundary_key: args.boundary_key && !safe_mode(),
+            duration_jitter_ms: args.duration_jitter_ms,
+            countdown_jitter_ms: args.countdown_jitter_ms,
+            baseline: args.baseline_secs.map(|secs| {
+                (
+                    args.baseline_text
+                        .clone()
+                        .unwrap_or_else(|| BASELINE_TEXT.to_string()),
+                    Duration::from_secs(secs),
+                    args.baseline_label.clone(),
+                )
+            }),
+            cooldown: args
+                .cooldown_secs
+                .map(|secs| (Duration::from_secs(secs), args.cooldown_label.clone())),
+            beeps: if args.beeps {
+                beep_patterns(&args.beep_patterns)
+            } else {
+                Vec::new()
+            },
+            lead_in: if args.prepare_label {
+                Activity::PREPARE
+            } else {
+                Activity::OTHER
+            },
+            time_scale: 1.0,
+        }
+    }
+
+    fn scaled(&self, duration: Duration) -> Duration {
+        duration.mul_f64(self.time_scale)
+    }
+}
+
+/// A dry run of the protocol with a fresh seed, labels going nowhere. `configure`
+/// adjusts the protocol before it runs.
+fn run_dry(args: &Args, activities: &[Activity], configure: impl FnOnce(&mut Protocol)) {
+    let mut protocol = Protocol::new(args, activities.to_vec(), args.order, rand::random());
+    // nothing is read, so windows can't be counted in samples
+    protocol.samples_per_activity = None;
+    configure(&mut protocol);
+    let labels = LabelFile {
+        file: Mutex::new(Box::new(io::sink())),
+        style: LabelStyle::Code,
+        time_origin: 0,
+        readings: None,
+        #[cfg(feature = "sqlite")]
+        database: None,
+    };
+
+    run_protocol(&protocol, &labels, &SessionState::new(), None, None);
+    if !safe_mode() {
+        let _ = execute!(io::stdout(), cursor::Show);
+    }
+    println!();
+}
+
+/// --rehearse-countdown: the practice run sped up, with nothing waiting for a key press
+/// or an answer.
+fn rehearse(protocol: &mut Protocol) {
+    protocol.self_paced = false;
+    protocol.describe_other = false;
+    protocol.abort_key = false;
+    protocol.boundary_key = false;
+    protocol.time_scale = REHEARSAL_TIME_SCALE;
+}
+
+/// Takes the participant through the protocol, labelling each phase, and marks the
+/// session done at the end. The status line is kept up to date meanwhile.
+fn run_protocol(
+    protocol: &Protocol,
+    label_file: &LabelFile,
+    state: &SessionState,
+    events_file: Option<&mut File>,
+    transition_log: Option<&mut File>,
+) {
+    thread::scope(|scope| {
+        scope.spawn(|| show_status(state));
+        run_activities(protocol, label_file, state, events_file, transition_log);
+    });
+}
+
+fn run_activities(
+    protocol: &Protocol,
+    label_file: &LabelFile,
+    state: &SessionState,
+    mut events_file: Option<&mut File>,
+    mut transition_log: Option<&mut File>,
+) {
+    let mut out = io::stdout();
+    let mut rng = SessionRng::new(protocol.seed, state);
+
+    let mut activities = arrange_activities(&protocol.activities, protocol.order, &mut rng);
+    if !matches!(protocol.order, OrderPolicy::None) {
+        let codes: Vec<&str> = activities.iter().map(label_code).collect();
+        rng.note("order", codes.join(","));
+    }
+    if protocol.calibrate {
+        activities.insert(0, Activity::CALIBRATION);
+    }
+    state
+        .activity_count
+        .store(activities.len(), Ordering::Relaxed);
+    // --boundary-key presses so far
+    let mut boundaries = 0;
+    let mut text_deck = protocol.no_repeat_texts.then(TextDeck::default);
+
+    if !protocol.start_delay.is_zero() {
+        let _ = set_label(&Activity::OTHER, &[], label_file, state);
+        log_transition(&mut transition_log, "get_ready", &Activity::OTHER);
+        let _ = print_msg(
+            message("session.get_ready", "Get ready!").to_string(),
+            &mut out,
+        );
+        thread::sleep(protocol.scaled(protocol.start_delay));
+    }
+
+    if let Some((text, duration, label)) = &protocol.baseline {
+        let _ = show_baseline_msg(text, &mut out);
+        let _ = set_label(label, &[], label_file, state);
+        log_transition(&mut transition_log, "baseline", label);
+        thread::sleep(protocol.scaled(*duration));
+    }
+
+    for (i, activity) in activities.into_iter().enumerate() {
+        state.activity_index.store(i + 1, Ordering::Relaxed);
+        let hand = if protocol.randomize_hand
+            && !matches!(activity, Activity::NOTHING | Activity::OTHER)
+        {
+            let hand = HANDS.choose(&mut rng).copied();
+            rng.note("hand", hand.unwrap_or_default());
+            hand
+        } else {
+            None
+        };
+        let _ = set_label(&protocol.lead_in, &[], label_file, state);
+        log_transition(&mut transition_log, "prepare", &activity);
+        let description = if protocol.describe_other && matches!(activity, Activity::OTHER) {
+            prompt_other_description(&mut out).ok()
+        } else {
+            None
+        };
+        let mut label_extra: Vec<&str> = hand.iter().map(|h| &h[..1]).collect();
+        label_extra.extend(description.as_deref());
+
+        let countdown_msg = match &description {
+            Some(_) => message("prepare.described", "Get ready!").to_string(),
+            None => get_before_activity_msg(&activity, hand),
+        };
+        let _ = start_countdown(&countdown_msg, protocol, &mut rng, &mut out);
+        let shown = show_after_countdown_msg(
+            &activity,
+            description.as_deref(),
+            text_deck.as_mut(),
+            &mut rng,
+            &mut out,
+        );
+        if let (Some(file), Ok(shown)) = (events_file.as_mut(), &shown) {
+            let onset_us = shown.onset_us.saturating_sub(label_file.time_origin * 1000);
+            let _ = writeln!(file, "{};onset;{}", onset_us, label_code(&activity));
+        }
+        let _ = set_label(&activity, &label_extra, label_file, state);
+        log_transition(&mut transition_log, "active", &activity);
+        if let Some(pattern) = protocol
+            .beeps
+            .iter()
+            .find(|pattern| label_code(&pattern.activity) == label_code(&activity))
+        {
+            play_beeps(pattern);
+        }
+        if let Ok(Shown {
+            onset_us,
+            variant: Some(variant),
+            ..
+        }) = shown
+        {
+            let variant = variant.to_string();
+            let _ = write_label_annotation("variant", onset_us / 1000, &[&variant], label_file);
+        }
+        if let Ok(Shown {
+            onset_us,
+            text: Some(text),
+            ..
+        }) = shown
+        {
+            let text = text.to_string();
+            let _ = write_label_annotation("text", onset_us / 1000, &[&text], label_file);
+        }
+        let window_end = match (protocol.samples_per_activity, protocol.duration_jitter_ms) {
+            _ if protocol.self_paced => {
+                let _ = show_self_paced_hint(&mut out);
+                WindowEnd::Enter
+            }
+            (Some(count), _) => WindowEnd::Samples(count),
+            (None, Some(jitter_ms)) => {
+                let duration = jittered_duration(ACTIVITY_DURATION_SEC, jitter_ms, &mut rng);
+                let ms = duration.as_millis().to_string();
+                rng.note("duration_ms", &ms);
+                let _ = write_label_annotation("duration", now_ms(), &[&ms], label_file);
+                WindowEnd::At(Instant::now() + protocol.scaled(duration))
+            }
+            (None, None) => WindowEnd::At(Instant::now() + protocol.scaled(ACTIVITY_DURATION_SEC)),
+        };
+        let phrase = shown.ok().and_then(|shown| shown.phrase);
+        let mut captured = false;
+        if let Some(phrase) = phrase.filter(|_| !safe_mode())
+            && let Ok(Some(typed)) = capture_typing(&window_end, state, &mut out)
+        {
+            captured = true;
+            let distance = edit_distance(phrase, &typed);
+            let longest = phrase.chars().count().max(typed.chars().count()).max(1);
+            let accuracy = format!("{:.3}", 1.0 - distance as f64 / longest as f64);
+            let distance = distance.to_string();
+            let _ =
+                write_label_annotation("accuracy", now_ms(), &[&distance, &accuracy], label_file);
+        }
+        let typing = matches!(activity, Activity::TYPING | Activity::TYPING_EXACT);
+        if (protocol.abort_key || protocol.boundary_key) && !typing {
+            let _ = watch_window_keys(&window_end, state, protocol, label_file, &mut boundaries);
+        } else if !(captured && matches!(window_end, WindowEnd::Enter)) {
+            // the Enter that ended the typing also ends a self-paced window
+            window_end.wait(state);
+        }
+        if state.participant_abort.load(Ordering::Relaxed) {
+            let _ = set_label(&Activity::OTHER, &[], label_file, state);
+            log_transition(&mut transition_log, "aborted", &activity);
+            let _ = print_msg(
+                message("session.aborted", "Session ended.").to_string(),
+                &mut out,
+            );
+            return;
+        }
 
-    let label_file_path = recording_dir.join("labels.csv");
-    let mut label_file = File::create(label_file_path)?;
+        if let Some((duration, label)) = &protocol.cooldown {
+            let _ = set_label(label, &[], label_file, state);
+            log_transition(&mut transition_log, "cooldown", &activity);
+            let _ = print_msg(message("session.cooldown", "Relax!").to_string(), &mut out);
+            thread::sleep(protocol.scaled(*duration));
+        }
+    }
 
-    thread::spawn(move || {
-        let mut out = io::stdout();
-        let mut rng = rand::rng();
+    let _ = set_label(&Activity::OTHER, &[], label_file, state);
+    log_transition(&mut transition_log, "rest", &Activity::OTHER);
+    if !protocol.tail.is_zero() {
+        let _ = print_msg(message("session.rest", "Rest!").to_string(), &mut out);
+        thread::sleep(protocol.scaled(protocol.tail));
+    }
+    let _ = print_msg(
+        message("session.done", "Done!\nYou are amazing!").to_string(),
+        &mut out,
+    );
+    log_transition(&mut transition_log, "done", &Activity::OTHER);
+    state.done.store(true, Ordering::Relaxed);
+}
 
-        let mut activities: [Activity; 8] = ACTIVITIES_ARR.clone();
-        activities.shuffle(&mut rng);
+/// Bells rung when an activity starts, with --beeps.
+#[derive(Clone, Debug)]
+struct BeepPattern {
+    activity: Activity,
+    count: u32,
+    gap: Duration,
+}
 
-        for activity in activities {
-            write_label_to_file(&Activity::OTHER, &mut label_file);
-            start_countdown(&activity, &mut out);
-            show_after_countdown_msg(&activity, &mut out);
-            write_label_to_file(&activity, &mut label_file);
-            thread::sleep(ACTIVITY_DURATION_SEC);
-        }
+/// The default --beeps patterns with `overrides` swapped in.
+fn beep_patterns(overrides: &[BeepPattern]) -> Vec<BeepPattern> {
+    let activities = [
+        Activity::NOTHING,
+        Activity::TYPING,
+        Activity::SCROLLING,
+        Activity::FIDGETING,
+        Activity::TYPING_EXACT,
+        Activity::CALIBRATION,
+        Activity::OTHER,
+    ];
+    activities
+        .into_iter()
+        .zip(1..)
+        .map(|(activity, count)| {
+            overrides
+                .iter()
+                .rev()
+                .find(|pattern| label_code(&pattern.activity) == label_code(&activity))
+                .cloned()
+                .unwrap_or(BeepPattern {
+                    activity,
+                    count,
+                    gap: BEEP_GAP,
+                })
+        })
+        .collect()
+}
 
-        write_label_to_file(&Activity::OTHER, &mut label_file);
-        print_msg("Done!\nYou are amazing!".to_string(), &mut out);
+/// Rings the pattern on its own thread, so it doesn't hold up the activity.
+fn play_beeps(pattern: &BeepPattern) {
+    let (count, gap) = (pattern.count, pattern.gap);
+    thread::spawn(move || {
+        for i in 0..count {
+            if i > 0 {
+                thread::sleep(gap);
+            }
+            let mut out = io::stdout();
+            let _ = out.write_all(b"\x07");
+            let _ = out.flush();
+        }
     });
+}
 
-    let readings_file_path = recording_dir.join("readings.csv");
-    let readings_file = File::create(readings_file_path)?;
-
-    let mut buffered_writer = BufWriter::new(readings_file);
-
-    let mut skip_first_three = 0;
-    let mut counter = 0;
+/// Appends a line to the --transition-log, if there is one.
+fn log_transition(log: &mut Option<&mut File>, phase: &str, activity: &Activity) {
+    if let Some(file) = log {
+        let _ = writeln!(file, "{};{};{}", now_ms(), phase, message_key(activity));
+    }
+}
 
-    let mut reader = io::BufReader::new(port);
+/// When an activity window is over.
+enum WindowEnd {
+    At(Instant),
+    Samples(u64),
+    /// When the participant presses Enter, with --self-paced.
+    Enter,
+}
 
-    let mut line = String::new();
+impl WindowEnd {
+    fn reached(&self, state: &SessionState) -> bool {
+        match self {
+            WindowEnd::At(deadline) => Instant::now() >= *deadline,
+            WindowEnd::Samples(count) => {
+                state.label().samples >= *count || state.done.load(Ordering::Relaxed)
+            }
+            WindowEnd::Enter => state.done.load(Ordering::Relaxed),
+        }
+    }
 
-    loop {
-        line.clear();
-        match BufRead::read_line(&mut reader, &mut line) {
-            Ok(0) => {
-                break;
+    fn wait(&self, state: &SessionState) {
+        match self {
+            WindowEnd::At(deadline) => {
+                thread::sleep(deadline.saturating_duration_since(Instant::now()))
             }
-            Ok(_) => {
-                if skip_first_three < WARMUP_LINE_COUNT {
-                    skip_first_three += 1;
-                    continue;
-                }
-                if !line.trim().is_empty() {
-                    write!(buffered_writer, "{};{}", now_ms(), line)?;
-                    if counter > FLUSH_EVERY {
-                        buffered_writer.flush()?;
-                    }
-                }
+            WindowEnd::Samples(count) => state.wait_for_samples(*count),
+            WindowEnd::Enter if safe_mode() => {
+                let _ = read_stdin_line(&mut String::new());
             }
-            Err(_) => {
-                // eprintln!("Error reading line: {}", e);
+            WindowEnd::Enter => {
+                // keystrokes are echoed like with TYPING_EXACT, but not scored
+                let _ = capture_typing(self, state, &mut io::stdout());
             }
         }
-        counter += 1;
     }
-
-    buffered_writer.flush()?;
-
-    Ok(())
 }
 
-fn validate_dir(dir: &Path) -> io::Result<()> {
-    if !dir.exists() {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidInput,
-            format!(
-                "'{}' does not exist",
-                dir.to_str().unwrap_or("Failed to convert to string")
-            ),
-        ));
-    }
-    if !dir.is_dir() {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidInput,
-            format!(
-                "'{}' is not a directory",
-                dir.to_str().unwrap_or("Failed to convert to string")
-            ),
-        ));
+/// Waits for the window to end in raw mode, watching for the --abort-key and the
+/// --boundary-key. Ctrl+C is passed on as SIGINT once the terminal is restored.
+fn watch_window_keys(
+    window_end: &WindowEnd,
+    state: &SessionState,
+    protocol: &Protocol,
+    label_file: &LabelFile,
+    boundaries: &mut usize,
+) -> io::Result<()> {
+    terminal::enable_raw_mode()?;
+    let pressed = read_window_keys(window_end, state, protocol, label_file, boundaries);
+    terminal::disable_raw_mode()?;
+
+    match pressed? {
+        Some(KeyCode::Char('x')) => {
+            state.participant_abort.store(true, Ordering::Relaxed);
+            state.done.store(true, Ordering::Relaxed);
+        }
+        Some(_) => unsafe {
+            libc::raise(libc::SIGINT);
+        },
+        None => {}
     }
     Ok(())
 }
 
-fn next_numeric_subdir(base_dir: &Path) -> io::Result<PathBuf> {
-    let mut current_index = 1;
-
-    let entries = fs::read_dir(&base_dir)?;
-    for entry in entries {
-        match entry {
-            Ok(_) => current_index += 1,
-            Err(e) => eprintln!("Error reading entry: {}", e),
+/// Marks a `#boundary` for every b, and returns the key that interrupted the window,
+/// x or Ctrl+C, or `None` once it ended.
+fn read_window_keys(
+    window_end: &WindowEnd,
+    state: &SessionState,
+    protocol: &Protocol,
+    label_file: &LabelFile,
+    boundaries: &mut usize,
+) -> io::Result<Option<KeyCode>> {
+    while !window_end.reached(state) {
+        if !event::poll(Duration::from_millis(50))? {
+            continue;
+        }
+        let Event::Key(key) = event::read()? else {
+            continue;
+        };
+        if key.kind != KeyEventKind::Press {
+            continue;
+        }
+        match key.code {
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                return Ok(Some(key.code));
+            }
+            KeyCode::Char('x') | KeyCode::Char('X') if protocol.abort_key => {
+                return Ok(Some(KeyCode::Char('x')));
+            }
+            KeyCode::Char('b') | KeyCode::Char('B') if protocol.boundary_key => {
+                *boundaries += 1;
+                let index = boundaries.to_string();
+                let _ = write_label_annotation("boundary", now_ms(), &[&index], label_file);
+            }
+            KeyCode::Enter if matches!(window_end, WindowEnd::Enter) => break,
+            _ => continue,
         }
     }
+    Ok(None)
+}
 
-    Ok(base_dir.join(current_index.to_string()).to_path_buf())
+fn show_self_paced_hint(out: &mut Stdout) -> io::Result<()> {
+    let hint = message("session.press_enter", "Press Enter when you are done.");
+    if safe_mode() {
+        writeln!(out, "{}", hint)?;
+        return out.flush();
+    }
+    execute!(
+        out,
+        cursor::MoveToNextLine(1),
+        Print(hint),
+        cursor::MoveToNextLine(2)
+    )
 }
 
-fn now_ms() -> u128 {
-    SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .expect("Time went backwards")
-        .as_millis()
+/// Captures the participant's keystrokes in raw mode until they press Enter or the
+/// window ends, echoing them under the phrase. `None` if they pressed Ctrl+C, which is
+/// passed on as SIGINT once the terminal is restored.
+fn capture_typing(
+    window_end: &WindowEnd,
+    state: &SessionState,
+    out: &mut Stdout,
+) -> io::Result<Option<String>> {
+    terminal::enable_raw_mode()?;
+    let typed = read_keystrokes(window_end, state, out);
+    terminal::disable_raw_mode()?;
+    execute!(out, cursor::MoveToNextLine(1), cursor::Hide)?;
+
+    let typed = typed?;
+    if typed.is_none() {
+        // raw mode kept Ctrl+C from reaching the recording loop
+        unsafe { libc::raise(libc::SIGINT) };
+    }
+    Ok(typed)
 }
 
-fn prompt_choice(prompt: &str, allowed: &[&str], default_opt: Option<&str>) -> io::Result<String> {
-    let mut input = String::new();
-    loop {
-        print!("{}", prompt);
-        io::stdout().flush()?;
-        input.clear();
-        io::stdin().read_line(&mut input)?;
-        let mut s = input.trim().to_lowercase();
-        if s.is_empty() {
-            if let Some(d) = default_opt {
-                s = d.to_string();
+fn read_keystrokes(
+    window_end: &WindowEnd,
+    state: &SessionState,
+    out: &mut Stdout,
+) -> io::Result<Option<String>> {
+    let mut typed = String::new();
+    while !window_end.reached(state) {
+        if !event::poll(Duration::from_millis(50))? {
+            continue;
+        }
+        let Event::Key(key) = event::read()? else {
+            continue;
+        };
+        if key.kind != KeyEventKind::Press {
+            continue;
+        }
+        match key.code {
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                return Ok(None);
+            }
+            KeyCode::Char(c) => typed.push(c),
+            KeyCode::Backspace => {
+                typed.pop();
             }
+            KeyCode::Enter => break,
+            _ => continue,
         }
-        if allowed.contains(&s.as_str()) {
-            return Ok(s);
-        } else {
-            eprint!("Invalid input. Expected one of {:?}. Try again.\n", allowed);
-            io::stderr().flush()?;
+        execute!(
+            out,
+            cursor::MoveToColumn(0),
+            terminal::Clear(ClearType::CurrentLine),
+            Print(&typed)
+        )?;
+    }
+    Ok(Some(typed))
+}
+
+/// Levenshtein distance between `a` and `b`, counted in characters.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != *cb);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
         }
+        previous = current;
     }
+    previous[b.len()]
 }
 
-fn prompt_height(prompt: &str) -> io::Result<Option<String>> {
-    let mut input = String::new();
-    loop {
-        print!("{}", prompt);
-        io::stdout().flush()?;
-        input.clear();
-        io::stdin().read_line(&mut input)?;
-        let s = input.trim();
-        if s.is_empty() {
-            return Ok(None);
+fn arrange_activities(
+    activities: &[Activity],
+    order: OrderPolicy,
+    rng: &mut SessionRng,
+) -> Vec<Activity> {
+    match order {
+        OrderPolicy::Full => {
+            let mut activities = activities.to_vec();
+            activities.shuffle(rng);
+            activities
         }
-        match s.parse::<i32>() {
-            Ok(h) if (50..=300).contains(&h) => return Ok(Some(h.to_string())),
-            Ok(_) => {
-                eprint!("You sure? Height must be between 50 and 300cm. Try again: \n");
-                io::stderr().flush()?;
-            }
-            Err(_) => {
-                eprint!("Height must be a valid integer. Try again: \n");
-                io::stderr().flush()?;
+        OrderPolicy::ByType => {
+            let mut groups: Vec<Vec<Activity>> = Vec::new();
+            for activity in activities {
+                let group = groups
+                    .iter_mut()
+                    .find(|group| label_code(&group[0]) == label_code(activity));
+                match group {
+                    Some(group) => group.push(activity.clone()),
+                    None => groups.push(vec![activity.clone()]),
+                }
             }
+            groups.shuffle(rng);
+            groups.concat()
         }
+        OrderPolicy::None => activities.to_vec(),
     }
 }
 
-fn start_countdown(activity: &Activity, out: &mut Stdout) -> io::Result<()> {
-    execute!(out, cursor::Hide)?;
-
-    let activity_msg = get_before_activity_msg(activity);
+fn start_countdown(
+    activity_msg: &str,
+    protocol: &Protocol,
+    rng: &mut SessionRng,
+    out: &mut Stdout,
+) -> io::Result<()> {
+    if !safe_mode() {
+        execute!(out, cursor::Hide)?;
+    }
 
     for n in (1..=COUNTDOWN_FROM).rev() {
         let number_str = n.to_string();
         print_msg(activity_msg.to_string() + " " + &number_str, out)?;
-        thread::sleep(COUNTDOWN_DURATION_SEC);
+        let tick = match protocol.countdown_jitter_ms {
+            Some(jitter_ms) => {
+                let tick = jittered_duration(COUNTDOWN_DURATION_SEC, jitter_ms, rng);
+                rng.note("countdown_tick_ms", tick.as_millis());
+                tick
+            }
+            None => COUNTDOWN_DURATION_SEC,
+        };
+        thread::sleep(protocol.scaled(tick));
     }
 
     Ok(())
 }
 
-fn get_before_activity_msg(activity: &Activity) -> String {
+/// Looks up a --lang translation, falling back to the built-in text.
+fn message<'a>(key: &str, default: &'a str) -> &'a str {
+    match MESSAGES.get() {
+        Some(messages) => messages.get(key, default),
+        None => default,
+    }
+}
+
+/// Name of an activity in --lang keys.
+fn message_key(activity: &Activity) -> &'static str {
     match activity {
+        Activity::TYPING => "typing",
+        Activity::TYPING_EXACT => "typing_exact",
+        Activity::NOTHING => "nothing",
+        Activity::SCROLLING => "scrolling",
+        Activity::FIDGETING => "fidgeting",
+        Activity::CALIBRATION => "calibration",
+        Activity::OTHER => "other",
+        Activity::PREPARE => "prepare",
+        Activity::BASELINE => "baseline",
+        Activity::COOLDOWN => "cooldown",
+    }
+}
+
+fn get_before_activity_msg(activity: &Activity, hand: Option<&str>) -> String {
+    let msg = match activity {
         Activity::TYPING => "Prepare to type!",
+        Activity::TYPING_EXACT => "Prepare to type exactly!",
         Activity::NOTHING => "Prepare to nothing!",
         Activity::SCROLLING => "Prepare to scroll!",
         Activity::FIDGETING => "Prepare to fidget!",
-        Activity::OTHER => unreachable!(),
+        Activity::CALIBRATION => "Prepare to calibrate!",
+        Activity::OTHER => "Prepare to rest!",
+        Activity::PREPARE | Activity::BASELINE | Activity::COOLDOWN => unreachable!(),
+    };
+    let msg = message(&format!("prepare.{}", message_key(activity)), msg);
+
+    match hand {
+        Some(hand) => message("prepare_hand", "{msg} with your {hand} hand!")
+            .replace("{msg}", msg.trim_end_matches('!'))
+            .replace(
+                "{hand}",
+                &message(&format!("hand.{}", hand), hand).to_uppercase(),
+            ),
+        None => msg.to_string(),
+    }
+}
+
+fn get_start_msg(activity: &Activity, default: &'static str) -> &'static str {
+    message(&format!("start.{}", message_key(activity)), default)
+}
+
+/// What the participant was shown at the start of an activity.
+struct Shown {
+    /// When the instruction appeared, in microseconds.
+    onset_us: u128,
+    /// Which --instructions variant was shown, if any.
+    variant: Option<usize>,
+    /// Index of the text shown for TYPING.
+    text: Option<usize>,
+    /// The phrase to type for TYPING_EXACT.
+    phrase: Option<&'static str>,
+}
+
+/// The TEXTS not drawn yet in this round of --no-repeat-texts.
+#[derive(Default)]
+struct TextDeck {
+    remaining: Vec<usize>,
+    last: Option<usize>,
+}
+
+impl TextDeck {
+    fn draw(&mut self, rng: &mut SessionRng) -> usize {
+        if self.remaining.is_empty() {
+            self.remaining = (0..TEXTS.len()).collect();
+            self.remaining.shuffle(rng);
+            // texts are drawn from the back, which must not repeat the round's last one
+            if self.remaining.len() > 1 && self.remaining.last() == self.last.as_ref() {
+                self.remaining.swap(0, TEXTS.len() - 1);
+            }
+        }
+        let text = self.remaining.pop().expect("the deck was refilled");
+        self.last = Some(text);
+        text
     }
-    .to_string()
 }
 
-fn show_after_countdown_msg(activity: &Activity, out: &mut Stdout) -> io::Result<()> {
+/// Shows the instruction for `activity`, or the operator's `description` of it.
+fn show_after_countdown_msg(
+    activity: &Activity,
+    description: Option<&str>,
+    text_deck: Option<&mut TextDeck>,
+    rng: &mut SessionRng,
+    out: &mut Stdout,
+) -> io::Result<Shown> {
+    let pool = INSTRUCTIONS
+        .get()
+        .and_then(|instructions| instructions.pool(message_key(activity)));
+    let variant = pool.map(|pool| rng.random_range(0..pool.len()));
+    if let Some(variant) = variant {
+        rng.note("variant", variant);
+    }
+    let start_msg = |default: &'static str| match (pool, variant) {
+        (Some(pool), Some(variant)) => pool[variant].as_str(),
+        _ => get_start_msg(activity, default),
+    };
+    let mut text_index = None;
+    let mut phrase = None;
+    let shown = |text, phrase| Shown {
+        onset_us: now_us(),
+        variant,
+        text,
+        phrase,
+    };
+
     match activity {
         Activity::TYPING => {
-            let mut rng = rand::rng();
+            let index = match text_deck {
+                Some(deck) => deck.draw(rng),
+                None => rng.random_range(0..TEXTS.len()),
+            };
+            rng.note("text", index);
+            text_index = Some(index);
+            let text = TEXTS[index];
+
+            if safe_mode() {
+                writeln!(out, "{}\n\n{}\n", start_msg("Retype this:"), text)?;
+                return out.flush().map(|_| shown(text_index, phrase));
+            }
+            execute!(
+                out,
+                terminal::Clear(ClearType::All),
+                cursor::MoveTo(0, 0),
+                Print(start_msg("Retype this:")),
+                Print("\n\n"),
+                Print(text),
+                cursor::MoveToNextLine(2),
+                cursor::Show
+            )?;
 
-            let text = TEXTS.choose(&mut rng).unwrap();
+            Ok(())
+        }
+        Activity::TYPING_EXACT => {
+            let index = rng.random_range(0..PHRASES.len());
+            rng.note("phrase", index);
+            let text = *phrase.insert(PHRASES[index]);
 
+            if safe_mode() {
+                writeln!(out, "{}\n\n{}\n", start_msg("Type exactly:"), text)?;
+                return out.flush().map(|_| shown(text_index, phrase));
+            }
             execute!(
                 out,
                 terminal::Clear(ClearType::All),
                 cursor::MoveTo(0, 0),
-                Print("Retype this:\n\n"),
+                Print(start_msg("Type exactly:")),
+                Print("\n\n"),
                 Print(text),
                 cursor::MoveToNextLine(2),
                 cursor::Show
@@ -309,23 +5266,160 @@ fn show_after_countdown_msg(activity: &Activity, out: &mut Stdout) -> io::Result
 
             Ok(())
         }
-        Activity::NOTHING => print_msg("Do nothing!".to_string(), out),
-        Activity::SCROLLING => print_msg("Scroll!".to_string(), out),
-        Activity::FIDGETING => print_msg("Fidget!".to_string(), out),
-        Activity::OTHER => unreachable!(),
+        Activity::NOTHING => print_msg(start_msg("Do nothing!").to_string(), out),
+        Activity::SCROLLING => print_msg(start_msg("Scroll!").to_string(), out),
+        Activity::FIDGETING => print_msg(start_msg("Fidget!").to_string(), out),
+        Activity::CALIBRATION => {
+            if safe_mode() {
+                writeln!(
+                    out,
+                    "{}\n\n{}\n",
+                    start_msg("Calibrate:"),
+                    CALIBRATION_INSTRUCTIONS
+                )?;
+                return out.flush().map(|_| shown(text_index, phrase));
+            }
+            execute!(
+                out,
+                terminal::Clear(ClearType::All),
+                cursor::MoveTo(0, 0),
+                Print(start_msg("Calibrate:")),
+                Print("\n\n"),
+                Print(CALIBRATION_INSTRUCTIONS),
+                cursor::MoveToNextLine(2)
+            )?;
+
+            Ok(())
+        }
+        Activity::OTHER => match description {
+            Some(description) => print_msg(description.to_string(), out),
+            None => print_msg(start_msg("Rest!").to_string(), out),
+        },
+        Activity::PREPARE | Activity::BASELINE | Activity::COOLDOWN => unreachable!(),
+    }?;
+
+    // the message is flushed, so this is when the participant actually sees it
+    Ok(shown(text_index, phrase))
+}
+
+fn show_baseline_msg(text: &str, out: &mut Stdout) -> io::Result<()> {
+    if safe_mode() {
+        writeln!(out, "{}\n", text)?;
+        return out.flush();
+    }
+    execute!(
+        out,
+        cursor::Hide,
+        terminal::Clear(ClearType::All),
+        cursor::MoveTo(0, 0),
+        Print(text),
+        cursor::MoveToNextLine(2)
+    )
+}
+
+fn load_font(font: &str) -> Result<FIGfont, String> {
+    if font == "standard" {
+        return FIGfont::standard();
+    }
+    if Path::new(font).is_file() {
+        return FIGfont::from_file(font);
+    }
+
+    FIGLET_FONT_DIRS
+        .iter()
+        .map(|dir| Path::new(dir).join(format!("{}.flf", font)))
+        .find(|path| path.is_file())
+        .ok_or_else(|| "no such font".to_string())
+        .and_then(|path| FIGfont::from_file(&path.to_string_lossy()))
+}
+
+/// Rings the bell and flashes a notice on the bottom row once real samples start being
+/// written. Does nothing when stdout is not a terminal.
+fn announce_recording() -> io::Result<()> {
+    let mut out = io::stdout().lock();
+    if safe_mode() {
+        return writeln!(out, "Recording");
+    }
+    if !out.is_terminal() {
+        return Ok(());
+    }
+
+    let (_, rows) = terminal::size()?;
+    execute!(
+        out,
+        Print("\x07"),
+        cursor::SavePosition,
+        cursor::MoveTo(0, rows.saturating_sub(1)),
+        Print("● Recording"),
+        cursor::RestorePosition
+    )
+}
+
+/// Redraws "activity i/N, elapsed mm:ss, samples so far" and the current window's
+/// live rate on the row above the recording notice every second until the session is
+/// done. Does nothing when stdout is not a terminal.
+fn show_status(state: &SessionState) {
+    use std::fmt::Write;
+
+    if safe_mode() || !io::stdout().is_terminal() {
+        return;
+    }
+    let started_at = Instant::now();
+    let mut drawn_at: Option<Instant> = None;
+    while !state.done.load(Ordering::Relaxed) {
+        if drawn_at.is_none_or(|at| at.elapsed() >= STATUS_INTERVAL) {
+            drawn_at = Some(Instant::now());
+            let elapsed = started_at.elapsed().as_secs();
+            let mut status = format!(
+                "activity {}/{}, elapsed {:02}:{:02}, {} samples so far",
+                state.activity_index.load(Ordering::Relaxed),
+                state.activity_count.load(Ordering::Relaxed),
+                elapsed / 60,
+                elapsed % 60,
+                state.samples.load(Ordering::Relaxed)
+            );
+            if let Some(rate) = state.recent_rate() {
+                let _ = write!(status, ", {:.1} Hz now", rate);
+            }
+            let Ok((_, rows)) = terminal::size() else {
+                return;
+            };
+            let _ = execute!(
+                io::stdout().lock(),
+                cursor::SavePosition,
+                cursor::MoveTo(0, rows.saturating_sub(2)),
+                terminal::Clear(ClearType::CurrentLine),
+                Print(status),
+                cursor::RestorePosition
+            );
+        }
+        thread::sleep(Duration::from_millis(50));
     }
 }
 
+fn safe_mode() -> bool {
+    SAFE_MODE.load(Ordering::Relaxed)
+}
+
 fn print_msg(msg: String, out: &mut Stdout) -> io::Result<()> {
+    if safe_mode() {
+        writeln!(out, "{}", msg)?;
+        return out.flush();
+    }
     execute!(out, cursor::Hide)?;
 
-    let figure = FIGFONT.convert(&msg).unwrap();
+    // FIGlet fonts only cover ASCII, so translated messages may need to go out as is
+    let figure = FIGFONT
+        .get_or_init(|| FIGfont::standard().expect("Failed to load FIGfont"))
+        .convert(&msg)
+        .filter(|_| msg.is_ascii())
+        .map_or_else(|| format!("{}\n", msg), |figure| figure.to_string());
 
     execute!(
         out,
         terminal::Clear(ClearType::All),
         cursor::MoveTo(0, 0),
-        Print(figure.to_string())
+        Print(figure)
     )?;
 
     out.flush()?;
@@ -333,14 +5427,320 @@ fn print_msg(msg: String, out: &mut Stdout) -> io::Result<()> {
     Ok(())
 }
 
-fn write_label_to_file(activity: &Activity, file: &mut File) -> io::Result<()> {
-    let s = match activity {
+fn label_code(activity: &Activity) -> &'static str {
+    match activity {
         Activity::TYPING => "t",
+        Activity::TYPING_EXACT => "e",
         Activity::SCROLLING => "s",
         Activity::FIDGETING => "f",
         Activity::NOTHING => "n",
+        Activity::CALIBRATION => "c",
         Activity::OTHER => "o",
-    };
-    writeln!(file, "{};{}", now_ms(), s)?;
+        Activity::PREPARE => "p",
+        Activity::BASELINE => "b",
+        Activity::COOLDOWN => "d",
+    }
+}
+
+/// Integer class id written with --numeric-labels.
+fn label_id(activity: &Activity) -> u8 {
+    match activity {
+        Activity::NOTHING => 0,
+        Activity::TYPING => 1,
+        Activity::TYPING_EXACT => 9,
+        Activity::SCROLLING => 2,
+        Activity::FIDGETING => 3,
+        Activity::OTHER => 4,
+        Activity::CALIBRATION => 5,
+        Activity::PREPARE => 6,
+        Activity::BASELINE => 7,
+        Activity::COOLDOWN => 8,
+    }
+}
+
+fn activity_from_label_code(code: &str) -> Option<Activity> {
+    if let Ok(id) = code.parse::<u8>() {
+        return ALL_ACTIVITIES
+            .iter()
+            .find(|activity| label_id(activity) == id)
+            .cloned();
+    }
+    if code.len() > 1 {
+        return ALL_ACTIVITIES
+            .iter()
+            .find(|activity| message_key(activity) == code)
+            .cloned();
+    }
+    match code {
+        "t" => Some(Activity::TYPING),
+        "e" => Some(Activity::TYPING_EXACT),
+        "s" => Some(Activity::SCROLLING),
+        "f" => Some(Activity::FIDGETING),
+        "n" => Some(Activity::NOTHING),
+        "c" => Some(Activity::CALIBRATION),
+        "o" => Some(Activity::OTHER),
+        "p" => Some(Activity::PREPARE),
+        "b" => Some(Activity::BASELINE),
+        "d" => Some(Activity::COOLDOWN),
+        _ => None,
+    }
+}
+
+/// How activities are written in labels.csv.
+enum LabelStyle {
+    /// The one-letter code.
+    Code,
+    /// The integer class id, with --numeric-labels.
+    Id,
+    /// The full name, with --label-names.
+    Name,
+}
+
+/// labels.csv together with the options that control how its rows are written. A session
+/// that ended cleanly (the protocol ran to its end, or a --raw-log was stopped with
+/// Ctrl+C) gets a last `<timestamp>;#end` row; crashed, interrupted or aborted ones
+/// never do.
+struct LabelFile {
+    file: Mutex<Box<dyn Write + Send>>,
+    style: LabelStyle,
+    /// Subtracted from every timestamp, see --relative-time.
+    time_origin: u128,
+    /// Gets every row as a marker too, with --inline-labels.
+    readings: Option<Arc<Mutex<ReadingsWriter>>>,
+    /// Also gets every row, with --sqlite.
+    #[cfg(feature = "sqlite")]
+    database: Option<Arc<sqlite::Database>>,
+}
+
+/// Writes a label and makes it the session's current activity.
+fn set_label(
+    activity: &Activity,
+    extra: &[&str],
+    file: &LabelFile,
+    state: &SessionState,
+) -> io::Result<()> {
+    state.set_activity(activity);
+    write_label_to_file(activity, extra, file)
+}
+
+fn write_label_to_file(activity: &Activity, extra: &[&str], file: &LabelFile) -> io::Result<()> {
+    match file.style {
+        LabelStyle::Code => write_label_row(now_ms(), label_code(activity), extra, file),
+        LabelStyle::Id => write_label_row(now_ms(), &label_id(activity).to_string(), extra, file),
+        LabelStyle::Name => write_label_row(now_ms(), message_key(activity), extra, file),
+    }
+}
+
+/// Writes a `#`-prefixed row to labels.csv. Annotations mark events and do not change
+/// the active label.
+fn write_label_annotation(
+    kind: &str,
+    timestamp: u128,
+    extra: &[&str],
+    file: &LabelFile,
+) -> io::Result<()> {
+    write_label_row(timestamp, &format!("#{}", kind), extra, file)
+}
+
+fn write_label_row(
+    timestamp: u128,
+    code: &str,
+    extra: &[&str],
+    file: &LabelFile,
+) -> io::Result<()> {
+    match &file.readings {
+        Some(readings) => write_label_row_with(
+            timestamp,
+            code,
+            extra,
+            file,
+            Some(&mut lock_readings(readings)),
+        ),
+        None => write_label_row_with(timestamp, code, extra, file, None),
+    }
+}
+
+/// [`write_label_row`] for a caller already holding the readings writer, which gets the
+/// --inline-labels marker.
+fn write_label_row_with(
+    timestamp: u128,
+    code: &str,
+    extra: &[&str],
+    file: &LabelFile,
+    readings: Option<&mut ReadingsWriter>,
+) -> io::Result<()> {
+    if let Some(readings) = readings {
+        readings.write_marker(timestamp, code, extra)?;
+    }
+    let timestamp = timestamp.saturating_sub(file.time_origin);
+    #[cfg(feature = "sqlite")]
+    if let Some(database) = &file.database {
+        database.insert_label(timestamp, code, extra);
+    }
+    let mut row = format!("{};{}", timestamp, code);
+    for field in extra {
+        row.push(';');
+        row.push_str(field);
+    }
+    let mut file = file.file.lock().unwrap_or_else(PoisonError::into_inner);
+    writeln!(file, "{}", row)?;
     Ok(())
 }
+
+fn is_label_annotation(row: &str) -> bool {
+    row.split(';')
+        .nth(1)
+        .is_some_and(|code| code.starts_with('#'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invalid_utf8_line_is_reported_lossily_and_reading_goes_on() {
+        let mut reader = io::Cursor::new(b"1.0,2.0\n\xff\xfe3.0,4.0\n5.0,6.0\n".to_vec());
+        let mut raw = Vec::new();
+
+        let (n, line) = read_device_line(&mut reader, &mut raw).unwrap();
+        assert_eq!((n, line), (8, Ok("1.0,2.0\n")));
+        raw.clear();
+
+        let (n, line) = read_device_line(&mut reader, &mut raw).unwrap();
+        assert_eq!(n, 10);
+        assert_eq!(line, Err("\u{fffd}\u{fffd}3.0,4.0\n".to_string()));
+        raw.clear();
+
+        let (_, line) = read_device_line(&mut reader, &mut raw).unwrap();
+        assert_eq!(line, Ok("5.0,6.0\n"));
+        raw.clear();
+
+        assert_eq!(read_device_line(&mut reader, &mut raw).unwrap().0, 0);
+    }
+
+    #[test]
+    fn averager_emits_the_mean_of_each_group_at_its_midpoint() {
+        let mut averager = Averager::new(2);
+        assert!(averager.push(100, "1,10\n").is_empty());
+        assert_eq!(averager.push(110, "3,20\n"), [(105, "2,15\n".to_string())]);
+
+        // a short group is flushed by finish, and only once
+        assert!(averager.push(200, "5;7\n").is_empty());
+        assert_eq!(averager.finish(), Some((200, "5,7\n".to_string())));
+        assert_eq!(averager.finish(), None);
+    }
+
+    #[test]
+    fn averager_closes_a_group_early_on_other_lines() {
+        let mut averager = Averager::new(3);
+        assert!(averager.push(0, "1,1\n").is_empty());
+        // a different column count starts a new group
+        assert_eq!(averager.push(10, "2,2,2\n"), [(0, "1,1\n".to_string())]);
+        // text goes through unchanged, after the group it ended
+        assert_eq!(
+            averager.push(20, "# banner\n"),
+            [(10, "2,2,2\n".to_string()), (20, "# banner\n".to_string())]
+        );
+        assert_eq!(averager.finish(), None);
+    }
+
+    #[test]
+    fn normalizer_formats_fields_and_counts_odd_rows() {
+        let mut stats = Stats::default();
+        let mut normalizer = Normalizer::new(Some(2), None);
+        assert_eq!(
+            normalizer.normalize("1, 2.345\n", &mut stats),
+            "1.00;2.35\n"
+        );
+        assert_eq!(normalizer.normalize("1 x 3\n", &mut stats), "1.00;NaN\n");
+        assert_eq!(normalizer.normalize("4\n", &mut stats), "4.00;NaN\n");
+        assert_eq!(stats.reshaped_rows, 2);
+        assert_eq!(stats.non_numeric_fields, 1);
+
+        let mut stats = Stats::default();
+        let mut normalizer = Normalizer::new(None, Some(3));
+        assert_eq!(
+            normalizer.normalize("0.50,1e3,-2\n", &mut stats),
+            "0.5;1000;-2\n"
+        );
+        assert_eq!(stats.reshaped_rows, 0);
+    }
+
+    #[test]
+    fn warmup_ends_after_its_lines() {
+        assert!(Warmup::new(WarmupLimit::Lines(0)).done);
+        assert!(Warmup::new(WarmupLimit::Time(Duration::ZERO)).done);
+
+        let mut warmup = Warmup::new(WarmupLimit::Lines(3));
+        assert!(!warmup.observe(true));
+        assert!(!warmup.observe(false));
+        assert!(warmup.observe(true));
+        assert_eq!((warmup.seen, warmup.valid), (3, 2));
+        assert_eq!(warmup.stabilized(), None);
+
+        warmup.restart();
+        assert_eq!((warmup.seen, warmup.done, warmup.retries), (0, false, 1));
+        warmup.rerun();
+        assert!(warmup.after_reconnect);
+        assert_eq!(warmup.retries, 1);
+    }
+
+    #[test]
+    fn stable_warmup_needs_valid_lines_in_a_row() {
+        let mut warmup = Warmup::new(WarmupLimit::Stable {
+            lines: 2,
+            timeout: Duration::from_secs(3600),
+        });
+        assert!(!warmup.observe(true));
+        assert!(!warmup.observe(false));
+        assert!(!warmup.observe(true));
+        assert!(warmup.observe(true));
+        assert_eq!(warmup.stabilized(), Some(true));
+
+        let mut warmup = Warmup::new(WarmupLimit::Stable {
+            lines: 2,
+            timeout: Duration::ZERO,
+        });
+        assert!(warmup.observe(false));
+        assert_eq!(warmup.stabilized(), Some(false));
+    }
+
+    #[test]
+    fn identifying_options_are_redacted_in_both_forms() {
+        let argv: Vec<String> = [
+            "--dir",
+            "out",
+            "--participant-id",
+            "P01",
+            "--operator=Kim",
+            "--practice",
+        ]
+        .iter()
+        .map(|arg| arg.to_string())
+        .collect();
+        assert_eq!(redact_identifiers(&argv), ["--dir", "out", "--practice"]);
+        assert_eq!(redact_identifiers(&argv[..3]), ["--dir", "out"]);
+    }
+
+    #[test]
+    fn options_given_now_replace_the_replayed_ones() {
+        let strings = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+        let replayed = strings(&["--dir", "old", "--baud=9600", "--practice"]);
+
+        let argv = override_options(&replayed, &strings(&["--repeat-last", "--dir=new"]));
+        assert_eq!(argv, strings(&["--baud=9600", "--practice", "--dir=new"]));
+        let args = Args::try_parse_from(std::iter::once("x".to_string()).chain(argv)).unwrap();
+        assert_eq!(args.dir, Some(PathBuf::from("new")));
+
+        let argv = override_options(&replayed, &strings(&["--repeat-last", "--baud", "115200"]));
+        assert_eq!(
+            argv,
+            strings(&["--dir", "old", "--practice", "--baud", "115200"])
+        );
+
+        assert_eq!(
+            override_options(&replayed, &strings(&["--repeat-last"])),
+            replayed
+        );
+    }
+}
@@ -0,0 +1,242 @@
+use crate::validate::read_readings;
+use crate::{
+    has_embedded_subject, is_label_annotation, parse_reading_row, split_reading_fields,
+    split_reading_row,
+};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// chars.txt keys copied onto every merged row.
+const SUBJECT_KEYS: [&str; 3] = ["sex", "hand", "height"];
+
+/// Writes the readings of every recording under `dirs` to one CSV, each row prefixed
+/// with its session id and the subject's attributes.
+pub fn run(dirs: &[PathBuf], out: &Path, pad: bool) -> io::Result<()> {
+    let mut found = Vec::new();
+    for dir in dirs {
+        find_recordings(dir, &mut found)?;
+    }
+    let root = common_root(&found);
+
+    let mut sessions = Vec::new();
+    for dir in found {
+        match read_readings(&dir) {
+            Ok(readings) => sessions.push((has_embedded_subject(&dir), dir, readings)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                eprintln!("Skipping '{}': {}", dir.display(), e)
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    if sessions.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "no recordings found (a recording directory holds chars.txt)",
+        ));
+    }
+
+    // first pass, so the column count is known before anything is written
+    let mut columns: Option<(usize, &Path)> = None;
+    for (embedded_subject, dir, readings) in &sessions {
+        for row in readings.iter().map(|line| line.row.as_str()) {
+            let Some((_, fields)) = parse_reading_row(row, *embedded_subject) else {
+                continue;
+            };
+            match columns {
+                Some((count, first)) if pad => columns = Some((count.max(fields.len()), first)),
+                Some((count, first)) if count != fields.len() => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "'{}' has a row with {} fields, expected {} as in '{}'; pass --pad to merge anyway",
+                            dir.display(),
+                            fields.len(),
+                            count,
+                            first.display()
+                        ),
+                    ));
+                }
+                Some(_) => {}
+                None => columns = Some((fields.len(), dir)),
+            }
+        }
+    }
+    let columns = columns.map_or(0, |(count, _)| count);
+
+    let mut writer = BufWriter::new(File::create(out)?);
+    let mut header = vec!["session_id".to_string(), "timestamp".to_string()];
+    header.extend(SUBJECT_KEYS.iter().map(|key| key.to_string()));
+    header.extend((1..=columns).map(|i| format!("v{}", i)));
+    writeln!(writer, "{}", header.join(";"))?;
+
+    let mut rows = 0;
+    let mut skipped = 0;
+    for (embedded_subject, dir, readings) in &sessions {
+        let session_id = session_id(&root, dir);
+        let chars = read_chars(dir)?;
+        let subject: Vec<&str> = SUBJECT_KEYS
+            .iter()
+            .map(|key| chars.get(*key).map_or("", String::as_str))
+            .collect();
+
+        for row in readings.iter().map(|line| line.row.as_str()) {
+            if row.starts_with('#') || is_label_annotation(row) {
+                continue;
+            }
+            if parse_reading_row(row, *embedded_subject).is_none() {
+                skipped += 1;
+                continue;
+            }
+            let (timestamp, line) = split_reading_row(row, *embedded_subject).unwrap_or_default();
+            let fields: Vec<&str> = split_reading_fields(line).collect();
+
+            write!(writer, "{};{};{}", session_id, timestamp, subject.join(";"))?;
+            for field in &fields {
+                write!(writer, ";{}", field)?;
+            }
+            for _ in fields.len()..columns {
+                write!(writer, ";")?;
+            }
+            writeln!(writer)?;
+            rows += 1;
+        }
+    }
+    writer.flush()?;
+
+    println!(
+        "Merged {} rows from {} recordings into {}",
+        rows,
+        sessions.len(),
+        out.display()
+    );
+    if skipped > 0 {
+        eprintln!("Skipped {} rows that were not numeric readings", skipped);
+    }
+    Ok(())
+}
+
+/// Collects `dir` if it is a recording, otherwise the recordings below it, in
+/// recording-number order.
+fn find_recordings(dir: &Path, found: &mut Vec<PathBuf>) -> io::Result<()> {
+    if dir.join("chars.txt").is_file() {
+        found.push(dir.to_path_buf());
+        return Ok(());
+    }
+
+    let mut children: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_dir())
+        .collect();
+    children.sort_by_key(|path| {
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let number = name.split('_').next().and_then(|n| n.parse::<u32>().ok());
+        (number.is_none(), number, name)
+    });
+    for child in children {
+        find_recordings(&child, found)?;
+    }
+    Ok(())
+}
+
+/// The deepest directory holding every recording, or all of their parents.
+fn common_root(recordings: &[PathBuf]) -> PathBuf {
+    let mut parents = recordings.iter().map(|dir| {
+        absolute(dir)
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default()
+    });
+    let Some(mut root) = parents.next() else {
+        return PathBuf::new();
+    };
+    for parent in parents {
+        while !parent.starts_with(&root) {
+            if !root.pop() {
+                break;
+            }
+        }
+    }
+    root
+}
+
+/// Names a recording by its path below `root`, so the `1` of two dated --dir-template
+/// dirs (`2026-10-14/1`, `2026-10-15/1`) stay apart.
+fn session_id(root: &Path, dir: &Path) -> String {
+    let dir = absolute(dir);
+    let relative = dir.strip_prefix(root).unwrap_or(&dir);
+    relative
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn absolute(dir: &Path) -> PathBuf {
+    fs::canonicalize(dir).unwrap_or_else(|_| dir.to_path_buf())
+}
+
+fn read_chars(dir: &Path) -> io::Result<HashMap<String, String>> {
+    Ok(fs::read_to_string(dir.join("chars.txt"))?
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sessions_are_named_below_their_common_root() {
+        let recordings = [
+            PathBuf::from("/no/such/study/2026-10-14/1"),
+            PathBuf::from("/no/such/study/2026-10-15/1"),
+        ];
+        let root = common_root(&recordings);
+        assert_eq!(root, Path::new("/no/such/study"));
+        assert_eq!(session_id(&root, &recordings[0]), "2026-10-14/1");
+        assert_eq!(session_id(&root, &recordings[1]), "2026-10-15/1");
+
+        let root = common_root(&recordings[..1]);
+        assert_eq!(root, Path::new("/no/such/study/2026-10-14"));
+        assert_eq!(session_id(&root, &recordings[0]), "1");
+        assert_eq!(common_root(&[]), PathBuf::new());
+    }
+
+    #[test]
+    fn merged_rows_carry_session_and_subject() {
+        let base = std::env::temp_dir().join(format!("fidgetsense-merge-{}", std::process::id()));
+        for (dir, chars, readings) in [
+            ("2_b", "sex=m\nhand=left\n", "30;5,6\n"),
+            (
+                "1_a",
+                "sex=f\nhand=right\nheight=170\n",
+                "10;1,2\n#end\n20;3\n",
+            ),
+        ] {
+            fs::create_dir_all(base.join(dir)).unwrap();
+            fs::write(base.join(dir).join("chars.txt"), chars).unwrap();
+            fs::write(base.join(dir).join("readings.csv"), readings).unwrap();
+        }
+        let out = base.join("merged.csv");
+
+        let err = run(std::slice::from_ref(&base), &out, false).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        run(std::slice::from_ref(&base), &out, true).unwrap();
+        assert_eq!(
+            fs::read_to_string(&out).unwrap(),
+            "session_id;timestamp;sex;hand;height;v1;v2\n\
+             1_a;10;f;right;170;1;2\n\
+             1_a;20;f;right;170;3;\n\
+             2_b;30;m;left;;5;6\n"
+        );
+        fs::remove_dir_all(&base).unwrap();
+    }
+}
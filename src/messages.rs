@@ -0,0 +1,192 @@
+//! Participant-facing messages loaded from a `--lang` file.
+//!
+//! The file is a small TOML subset: `[section]` headers and `key = "value"` lines,
+//! with `#` comments. Keys are `<phase>.<activity>`, e.g.
+//!
+//! ```toml
+//! [prepare]
+//! typing = "Bereite dich aufs Tippen vor!"
+//!
+//! [start]
+//! scrolling = "Scrollen!"
+//! ```
+//!
+//! Hand instructions use `prepare_hand = "{msg} with your {hand} hand!"` plus
+//! `hand.left` / `hand.right`, and the session screens are `session.get_ready`,
+//! `session.rest` and `session.done`. Missing keys fall back to the built-in English text.
+//!
+//! The same format holds `--instructions` pools, where a key maps to an array of
+//! variants: `fidgeting = ["Tap your foot!", "Click a pen!"]`.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+#[derive(Debug, Default)]
+pub struct Messages {
+    map: HashMap<String, String>,
+    pools: HashMap<String, Vec<String>>,
+}
+
+impl Messages {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let contents = fs::read_to_string(path)?;
+        parse(&contents).map_err(|(line, problem)| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{}:{}: {}", path.display(), line, problem),
+            )
+        })
+    }
+
+    /// The translation for `key`, or `default` when the file doesn't have one.
+    pub fn get<'a>(&'a self, key: &str, default: &'a str) -> &'a str {
+        self.map.get(key).map_or(default, String::as_str)
+    }
+
+    /// The variants listed for `key`, if it holds a non-empty array.
+    pub fn pool(&self, key: &str) -> Option<&[String]> {
+        self.pools
+            .get(key)
+            .map(Vec::as_slice)
+            .filter(|pool| !pool.is_empty())
+    }
+}
+
+fn parse(contents: &str) -> Result<Messages, (usize, String)> {
+    let mut map = HashMap::new();
+    let mut pools = HashMap::new();
+    let mut section = String::new();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[') {
+            let name = name
+                .strip_suffix(']')
+                .ok_or_else(|| (i + 1, "unterminated section header".to_string()))?;
+            section = name.trim().to_string();
+            continue;
+        }
+
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| (i + 1, "expected key = \"value\"".to_string()))?;
+        let key = key.trim();
+        let key = if section.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}.{}", section, key)
+        };
+        let value = value.trim();
+        if value.starts_with('[') {
+            let pool = parse_array(value).map_err(|problem| (i + 1, problem))?;
+            pools.insert(key, pool);
+        } else {
+            let (value, rest) = parse_string(value).map_err(|problem| (i + 1, problem))?;
+            check_trailing(rest).map_err(|problem| (i + 1, problem))?;
+            map.insert(key, value);
+        }
+    }
+    Ok(Messages { map, pools })
+}
+
+/// Parses a one-line `["a", "b"]` array of strings.
+fn parse_array(s: &str) -> Result<Vec<String>, String> {
+    let mut rest = s[1..].trim_start();
+    let mut values = Vec::new();
+    loop {
+        if let Some(after) = rest.strip_prefix(']') {
+            check_trailing(after)?;
+            return Ok(values);
+        }
+        let (value, after) = parse_string(rest)?;
+        values.push(value);
+        rest = after.trim_start();
+        if let Some(after) = rest.strip_prefix(',') {
+            rest = after.trim_start();
+        } else if !rest.starts_with(']') {
+            return Err("expected ',' or ']' in the array".to_string());
+        }
+    }
+}
+
+/// Parses a double-quoted string with the usual escapes, returning it and the rest of
+/// the line.
+fn parse_string(s: &str) -> Result<(String, &str), String> {
+    let mut chars = s
+        .strip_prefix('"')
+        .ok_or_else(|| "values must be double-quoted strings".to_string())?
+        .chars();
+    let mut value = String::new();
+    loop {
+        match chars.next() {
+            Some('"') => break,
+            Some('\\') => match chars.next() {
+                Some('n') => value.push('\n'),
+                Some('t') => value.push('\t'),
+                Some('"') => value.push('"'),
+                Some('\\') => value.push('\\'),
+                Some(other) => return Err(format!("unknown escape '\\{}'", other)),
+                None => return Err("unterminated string".to_string()),
+            },
+            Some(c) => value.push(c),
+            None => return Err("unterminated string".to_string()),
+        }
+    }
+
+    Ok((value, chars.as_str()))
+}
+
+/// Only a comment may follow a value.
+fn check_trailing(rest: &str) -> Result<(), String> {
+    let rest = rest.trim();
+    if !rest.is_empty() && !rest.starts_with('#') {
+        return Err(format!("unexpected '{}' after the value", rest));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse;
+
+    #[test]
+    fn sections_strings_and_arrays() {
+        let messages = parse(
+            "# comment\n\
+             top = \"x\"\n\
+             [prepare]\n\
+             typing = \"Tipp \\\"los\\\"\\n\" # trailing comment\n\
+             [ pools ]\n\
+             fidgeting = [\"Tap your foot!\", \"Click, a pen!\" ]\n\
+             empty = []\n",
+        )
+        .unwrap();
+        assert_eq!(messages.get("top", "-"), "x");
+        assert_eq!(messages.get("prepare.typing", "-"), "Tipp \"los\"\n");
+        assert_eq!(messages.get("prepare.scrolling", "default"), "default");
+        assert_eq!(
+            messages.pool("pools.fidgeting"),
+            Some(&["Tap your foot!".to_string(), "Click, a pen!".to_string()][..])
+        );
+        assert_eq!(messages.pool("pools.empty"), None);
+    }
+
+    #[test]
+    fn errors_name_the_line() {
+        let problem = |contents| parse(contents).unwrap_err();
+        assert_eq!(problem("a = \"x\"\n[open\n").0, 2);
+        assert_eq!(problem("a = x").1, "values must be double-quoted strings");
+        assert_eq!(problem("a = \"x").1, "unterminated string");
+        assert_eq!(problem("a = \"\\q\"").1, "unknown escape '\\q'");
+        assert_eq!(problem("a = \"x\" y").1, "unexpected 'y' after the value");
+        assert_eq!(
+            problem("a = [\"x\" \"y\"]").1,
+            "expected ',' or ']' in the array"
+        );
+        assert_eq!(problem("just words").1, "expected key = \"value\"");
+    }
+}
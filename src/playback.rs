@@ -0,0 +1,131 @@
+use crate::validate::read_readings;
+use crate::{
+    Activity, LABELS_HEADER, has_embedded_subject, is_label_annotation, label_code,
+    parse_label_row, parse_reading_row,
+};
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+const BAR_WIDTH: usize = 20;
+/// Longer pauses between readings are cut short, so a gap doesn't stall the preview.
+const MAX_PAUSE: Duration = Duration::from_secs(1);
+
+/// Replays a recording as one line per `interval_ms` of readings, each channel drawn as
+/// the range its values covered, at `speed` times the original pace.
+pub fn run(dir: &Path, speed: f64, interval_ms: u128) -> io::Result<()> {
+    let readings = read_readings(dir)?;
+    let embedded_subject = has_embedded_subject(dir);
+    let rows: Vec<(u128, Vec<f64>)> = readings
+        .iter()
+        .filter_map(|line| parse_reading_row(&line.row, embedded_subject))
+        .collect();
+    let Some(&(start, _)) = rows.first() else {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("no readings to play back in '{}'", dir.display()),
+        ));
+    };
+    let labels = read_labels(dir);
+
+    // every channel is scaled to its range over the whole recording
+    let channels = rows
+        .iter()
+        .map(|(_, fields)| fields.len())
+        .max()
+        .unwrap_or(0);
+    let mut ranges = vec![(f64::INFINITY, f64::NEG_INFINITY); channels];
+    for (_, fields) in &rows {
+        for (range, value) in ranges.iter_mut().zip(fields) {
+            *range = (range.0.min(*value), range.1.max(*value));
+        }
+    }
+
+    let mut out = io::stdout().lock();
+    let mut shown_until = start;
+    // rows may step back in time (--backwards-timestamps flag), which counts as no time
+    let interval = |t: u128| t.saturating_sub(start) / interval_ms;
+    for group in rows.chunk_by(|a, b| interval(a.0) == interval(b.0)) {
+        let at = group[0].0;
+        let pause = Duration::from_millis(at.saturating_sub(shown_until) as u64).div_f64(speed);
+        thread::sleep(pause.min(MAX_PAUSE));
+        shown_until = at;
+
+        let activity = labels
+            .iter()
+            .take_while(|(t, _)| *t <= at)
+            .last()
+            .map_or("-", |(_, activity)| label_code(activity));
+        let mut line = format!(
+            "{:9.3}s {:>2} ",
+            at.saturating_sub(start) as f64 / 1000.0,
+            activity
+        );
+        for (channel, range) in ranges.iter().enumerate() {
+            let values = group.iter().filter_map(|(_, fields)| fields.get(channel));
+            let (low, high) = values.fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), v| {
+                (lo.min(*v), hi.max(*v))
+            });
+            line.push('|');
+            line.push_str(&bar(low, high, *range));
+        }
+        line.push('|');
+        writeln!(out, "{}", line)?;
+    }
+    Ok(())
+}
+
+/// Marks the cells between `low` and `high` on a bar spanning `range`.
+fn bar(low: f64, high: f64, range: (f64, f64)) -> String {
+    if low > high {
+        return " ".repeat(BAR_WIDTH);
+    }
+    let span = range.1 - range.0;
+    let cell = |value: f64| {
+        if span > 0.0 {
+            (((value - range.0) / span) * (BAR_WIDTH - 1) as f64).round() as usize
+        } else {
+            BAR_WIDTH / 2
+        }
+    };
+    let (from, to) = (cell(low), cell(high));
+    (0..BAR_WIDTH)
+        .map(|i| if i >= from && i <= to { '=' } else { ' ' })
+        .collect()
+}
+
+fn read_labels(dir: &Path) -> Vec<(u128, Activity)> {
+    fs::read_to_string(dir.join("labels.csv"))
+        .unwrap_or_default()
+        .lines()
+        .filter(|row| !is_label_annotation(row) && *row != LABELS_HEADER)
+        .filter_map(parse_label_row)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bar_covers_the_cells_between_low_and_high() {
+        assert_eq!(bar(0.0, 10.0, (0.0, 10.0)), "=".repeat(BAR_WIDTH));
+        assert_eq!(bar(0.0, 0.0, (0.0, 19.0)), format!("={}", " ".repeat(19)));
+        assert_eq!(
+            bar(5.0, 9.0, (0.0, 19.0)),
+            format!("{}{}{}", " ".repeat(5), "=".repeat(5), " ".repeat(10))
+        );
+        // nothing seen yet
+        assert_eq!(
+            bar(f64::INFINITY, f64::NEG_INFINITY, (0.0, 1.0)),
+            " ".repeat(BAR_WIDTH)
+        );
+        // a flat signal sits in the middle
+        assert_eq!(
+            bar(3.0, 3.0, (3.0, 3.0)),
+            format!("{}={}", " ".repeat(10), " ".repeat(9))
+        );
+    }
+}
@@ -0,0 +1,189 @@
+use crate::{hash_participant_id, load_or_create_salt};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Action {
+    Keep,
+    Drop,
+    Hash,
+}
+
+/// Whether chars.txt `key` is named by `field`, where a trailing `*` matches any rest
+/// (`env.*`).
+fn matches(field: &str, key: &str) -> bool {
+    match field.strip_suffix('*') {
+        Some(prefix) => key.starts_with(prefix),
+        None => key == field,
+    }
+}
+
+/// Copies the recording in `dir` to `out` with the `drop` fields left out of chars.txt
+/// and the `hash` fields replaced by their salted SHA-256, in the `arg=` lines too. The
+/// original is not touched.
+pub fn run(
+    dir: &Path,
+    out: &Path,
+    drop: &[String],
+    hash: &[String],
+    salt_file: &Path,
+) -> io::Result<()> {
+    let chars = fs::read_to_string(dir.join("chars.txt"))?;
+    if out.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("'{}' already exists", out.display()),
+        ));
+    }
+
+    let salt = match hash.is_empty() {
+        true => String::new(),
+        false => load_or_create_salt(salt_file)?,
+    };
+    let action_for = |key: &str| {
+        if drop.iter().any(|field| matches(field, key)) {
+            Action::Drop
+        } else if hash.iter().any(|field| matches(field, key)) {
+            Action::Hash
+        } else {
+            Action::Keep
+        }
+    };
+
+    let mut scrubbed = String::new();
+    let (mut dropped, mut hashed) = (0, 0);
+    // set by an `arg=--<option>` line whose value is on the next arg line
+    let mut option_value = None;
+    for line in chars.lines() {
+        let Some((key, mut value)) = line.split_once('=') else {
+            scrubbed.push_str(line);
+            scrubbed.push('\n');
+            continue;
+        };
+        let mut prefix = format!("{}=", key);
+        let mut action = action_for(key);
+        if key == "arg" && action == Action::Keep {
+            // an option named like a scrubbed key (--participant-id for participant_id)
+            // takes its value along, whether it follows after `=` or as the next arg
+            if let Some(pending) = option_value.take() {
+                action = pending;
+            } else if let Some(option) = value.strip_prefix("--") {
+                match option.split_once('=') {
+                    Some((name, inline)) => {
+                        action = action_for(&name.replace('-', "_"));
+                        prefix = format!("arg=--{}=", name);
+                        value = inline;
+                    }
+                    None => {
+                        let option_action = action_for(&option.replace('-', "_"));
+                        if option_action != Action::Keep {
+                            option_value = Some(option_action);
+                        }
+                        if option_action == Action::Drop {
+                            action = Action::Drop;
+                        }
+                    }
+                }
+            }
+        }
+        match action {
+            Action::Keep => {
+                scrubbed.push_str(line);
+                scrubbed.push('\n');
+            }
+            Action::Drop => dropped += 1,
+            Action::Hash => {
+                scrubbed.push_str(&format!(
+                    "{}{}\n",
+                    prefix,
+                    hash_participant_id(value, &salt)
+                ));
+                hashed += 1;
+            }
+        }
+    }
+
+    copy_dir(dir, out)?;
+    fs::write(out.join("chars.txt"), scrubbed)?;
+    println!(
+        "Wrote '{}' ({} lines dropped, {} hashed)",
+        out.display(),
+        dropped,
+        hashed
+    );
+    Ok(())
+}
+
+fn copy_dir(from: &Path, to: &Path) -> io::Result<()> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir(&entry.path(), &target)?;
+        } else {
+            fs::copy(entry.path(), target)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fields_match_whole_keys_or_a_prefix() {
+        assert!(matches("operator", "operator"));
+        assert!(!matches("operator", "operator_id"));
+        assert!(matches("env.*", "env.host"));
+        assert!(!matches("env.*", "environment"));
+        assert!(matches("*", "anything"));
+    }
+
+    #[test]
+    fn scrubbed_copy_drops_and_hashes_fields_and_their_args() {
+        let base = std::env::temp_dir().join(format!("fidgetsense-scrub-{}", std::process::id()));
+        let (dir, out) = (base.join("1"), base.join("1-scrubbed"));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("salt"), "pepper\n").unwrap();
+        fs::write(dir.join("readings.csv"), "1;2\n").unwrap();
+        fs::write(
+            dir.join("chars.txt"),
+            "sex=f\nparticipant_id=P01\noperator=Kim\nenv.host=lab\n\
+             arg=--participant-id\narg=P01\narg=--operator=Kim\narg=--practice\n",
+        )
+        .unwrap();
+
+        run(
+            &dir,
+            &out,
+            &["operator".to_string(), "env.*".to_string()],
+            &["participant_id".to_string()],
+            &dir.join("salt"),
+        )
+        .unwrap();
+        let chars = fs::read_to_string(out.join("chars.txt")).unwrap();
+        let hash = hash_participant_id("P01", "pepper");
+        assert_eq!(
+            chars,
+            format!(
+                "sex=f\nparticipant_id={hash}\narg=--participant-id\narg={hash}\narg=--practice\n"
+            )
+        );
+        assert_eq!(fs::read(out.join("readings.csv")).unwrap(), b"1;2\n");
+        assert!(
+            fs::read_to_string(dir.join("chars.txt"))
+                .unwrap()
+                .contains("P01")
+        );
+        assert_eq!(
+            run(&dir, &out, &[], &[], &dir.join("salt"))
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::AlreadyExists
+        );
+        fs::remove_dir_all(&base).unwrap();
+    }
+}
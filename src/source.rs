@@ -0,0 +1,258 @@
+//! Where readings come from: a serial port, a TCP socket for boards that stream
+//! over the network, a FIFO some driver process writes into, or standard input for
+//! canned data. The read loop only sees a `Source`.
+
+use serial2::SerialPort;
+use std::fs::File;
+use std::io::{self, Read};
+use std::net::TcpStream;
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+
+pub trait Source: Read + Send {
+    /// Drops whatever arrived but hasn't been read yet.
+    fn discard_input(&mut self) -> io::Result<()>;
+}
+
+impl Source for SerialPort {
+    fn discard_input(&mut self) -> io::Result<()> {
+        self.discard_input_buffer()
+    }
+}
+
+/// Stands in for a serial port that went away until --reconnect reopens it.
+impl Source for io::Empty {
+    fn discard_input(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A TCP stream whose read timeouts surface as `TimedOut`, like a serial port's,
+/// rather than the platform's `WouldBlock`.
+pub struct TcpSource {
+    stream: TcpStream,
+}
+
+impl TcpSource {
+    pub fn connect(address: &str, read_timeout: Duration) -> io::Result<Self> {
+        let stream = TcpStream::connect(address)?;
+        stream.set_read_timeout(Some(read_timeout))?;
+        stream.set_nodelay(true)?;
+        Ok(TcpSource { stream })
+    }
+}
+
+impl Read for TcpSource {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.stream.read(buf) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                Err(io::Error::new(io::ErrorKind::TimedOut, e))
+            }
+            result => result,
+        }
+    }
+}
+
+impl Source for TcpSource {
+    fn discard_input(&mut self) -> io::Result<()> {
+        self.stream.set_nonblocking(true)?;
+        let mut buf = [0u8; 4096];
+        let result = loop {
+            match self.stream.read(&mut buf) {
+                Ok(0) => break Ok(()),
+                Ok(_) => continue,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break Ok(()),
+                Err(e) => break Err(e),
+            }
+        };
+        self.stream.set_nonblocking(false)?;
+        result
+    }
+}
+
+/// `host:port` when `dev` names a network address rather than a device path.
+pub fn tcp_address(dev: &str) -> Option<&str> {
+    if dev.starts_with('/') || dev.starts_with('.') {
+        return None;
+    }
+    let (host, port) = dev.rsplit_once(':')?;
+    (!host.is_empty() && port.parse::<u16>().is_ok()).then_some(dev)
+}
+
+/// Standard input or a FIFO, read on a thread so reads time out like a serial port's
+/// instead of blocking the read loop. The writer closing its end is the end of input.
+pub struct PipeSource {
+    chunks: Receiver<io::Result<Vec<u8>>>,
+    pending: Vec<u8>,
+    eof: bool,
+    read_timeout: Duration,
+}
+
+impl PipeSource {
+    pub fn stdin(read_timeout: Duration) -> Self {
+        Self::spawn(io::stdin(), read_timeout)
+    }
+
+    /// Opens the FIFO at `path`, which waits until a writer opens the other end.
+    pub fn fifo(path: &Path, read_timeout: Duration) -> io::Result<Self> {
+        Ok(Self::spawn(File::open(path)?, read_timeout))
+    }
+
+    /// Opens the FIFO at `path` again after its writer closed it, giving up when no new
+    /// writer opens the other end within `timeout`.
+    pub fn reopen_fifo(path: &Path, read_timeout: Duration, timeout: Duration) -> io::Result<Self> {
+        let (tx, opened) = mpsc::channel();
+        let path = path.to_path_buf();
+        // the open blocks until there is a writer; if none comes, the thread is left
+        // waiting and drops the file should one open it later
+        thread::spawn(move || {
+            let _ = tx.send(File::open(path));
+        });
+        match opened.recv_timeout(timeout) {
+            Ok(file) => Ok(Self::spawn(file?, read_timeout)),
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "no writer opened the FIFO",
+            )),
+        }
+    }
+
+    fn spawn(mut input: impl Read + Send + 'static, read_timeout: Duration) -> Self {
+        let (tx, chunks) = mpsc::channel();
+        thread::spawn(move || {
+            loop {
+                let mut buf = vec![0u8; 4096];
+                let chunk = match input.read(&mut buf) {
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Ok(n) => {
+                        buf.truncate(n);
+                        Ok(buf)
+                    }
+                    Err(e) => Err(e),
+                };
+                let last = !matches!(&chunk, Ok(buf) if !buf.is_empty());
+                if tx.send(chunk).is_err() || last {
+                    break;
+                }
+            }
+        });
+        PipeSource {
+            chunks,
+            pending: Vec::new(),
+            eof: false,
+            read_timeout,
+        }
+    }
+}
+
+impl Read for PipeSource {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pending.is_empty() && !self.eof {
+            match self.chunks.recv_timeout(self.read_timeout) {
+                Ok(Ok(chunk)) if chunk.is_empty() => self.eof = true,
+                Ok(Ok(chunk)) => self.pending = chunk,
+                Ok(Err(e)) => return Err(e),
+                Err(RecvTimeoutError::Timeout) => {
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, "no input"));
+                }
+                Err(RecvTimeoutError::Disconnected) => self.eof = true,
+            }
+        }
+
+        let n = buf.len().min(self.pending.len());
+        buf[..n].copy_from_slice(&self.pending[..n]);
+        self.pending.drain(..n);
+        Ok(n)
+    }
+}
+
+impl Source for PipeSource {
+    fn discard_input(&mut self) -> io::Result<()> {
+        self.pending.clear();
+        while let Ok(chunk) = self.chunks.try_recv() {
+            if !matches!(chunk, Ok(ref chunk) if !chunk.is_empty()) {
+                self.eof = true;
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether `dev` is `-`, meaning standard input.
+pub fn is_stdin(dev: &Path) -> bool {
+    dev.as_os_str() == "-"
+}
+
+/// Whether `dev` is a named pipe. Elsewhere than on Unix, that is a path in the
+/// `\\.\pipe\` namespace, which reads like any file.
+#[cfg(unix)]
+pub fn is_fifo(dev: &Path) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+    std::fs::metadata(dev).is_ok_and(|metadata| metadata.file_type().is_fifo())
+}
+
+#[cfg(not(unix))]
+pub fn is_fifo(dev: &Path) -> bool {
+    dev.to_str()
+        .is_some_and(|dev| dev.starts_with(r"\\.\pipe\"))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+    use std::io::BufRead;
+    use std::os::unix::ffi::OsStrExt;
+
+    fn read_to_end(source: PipeSource) -> String {
+        let mut reader = io::BufReader::new(source);
+        let mut text = String::new();
+        loop {
+            match reader.read_line(&mut text) {
+                Ok(0) => return text,
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => {}
+                Err(e) => panic!("{}", e),
+            }
+        }
+    }
+
+    #[test]
+    fn fifo_is_reopened_for_a_new_writer() {
+        let path = std::env::temp_dir().join(format!("fidgetsense-fifo-{}", std::process::id()));
+        let c_path = CString::new(path.as_os_str().as_bytes()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) }, 0);
+
+        let writer_path = path.clone();
+        let writer = thread::spawn(move || {
+            for text in ["first\n", "second\n"] {
+                let mut fifo = std::fs::OpenOptions::new()
+                    .write(true)
+                    .open(&writer_path)
+                    .unwrap();
+                io::Write::write_all(&mut fifo, text.as_bytes()).unwrap();
+                drop(fifo);
+                // lets the reader see the end of input before the next writer opens
+                thread::sleep(Duration::from_millis(300));
+            }
+        });
+
+        let timeout = Duration::from_millis(100);
+        let first = read_to_end(PipeSource::fifo(&path, timeout).unwrap());
+        let reopened = PipeSource::reopen_fifo(&path, timeout, Duration::from_secs(5));
+        let second = read_to_end(reopened.unwrap());
+        writer.join().unwrap();
+        let nobody = PipeSource::reopen_fifo(&path, timeout, Duration::from_millis(200));
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(first, "first\n");
+        assert_eq!(second, "second\n");
+        assert_eq!(
+            nobody.err().map(|e| e.kind()),
+            Some(io::ErrorKind::TimedOut)
+        );
+    }
+}
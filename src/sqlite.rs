@@ -0,0 +1,235 @@
+//! `--sqlite`: inserts readings, labels and the session's metadata into an SQLite
+//! database next to the CSV files. Statements are piped to the `sqlite3` command-line
+//! shell, which has to be installed. Rows are held back and committed in one
+//! transaction per flush of the readings. The database only mirrors the CSV files, so
+//! once `sqlite3` fails it is warned about and left behind while recording goes on.
+
+use std::io::{self, Write};
+use std::path::Path;
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+const SCHEMA: &str = "\
+CREATE TABLE IF NOT EXISTS sessions (
+    id TEXT PRIMARY KEY,
+    dir TEXT NOT NULL,
+    started_at INTEGER NOT NULL,
+    ended_at INTEGER,
+    metadata TEXT
+);
+CREATE TABLE IF NOT EXISTS readings (
+    session TEXT NOT NULL REFERENCES sessions(id),
+    timestamp INTEGER NOT NULL,
+    data TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS labels (
+    session TEXT NOT NULL REFERENCES sessions(id),
+    timestamp INTEGER NOT NULL,
+    label TEXT NOT NULL,
+    extra TEXT
+);
+CREATE INDEX IF NOT EXISTS readings_session ON readings(session, timestamp);
+CREATE INDEX IF NOT EXISTS labels_session ON labels(session, timestamp);
+";
+
+pub struct Database {
+    /// The session's random UUID, the key of its row in the `sessions` table.
+    id: String,
+    /// `id` quoted for SQL.
+    session: String,
+    shell: Mutex<Shell>,
+}
+
+struct Shell {
+    child: Child,
+    /// `None` once closed, or after a failed write stopped the mirror.
+    stdin: Option<ChildStdin>,
+    /// INSERTs waiting for the next commit.
+    pending: String,
+}
+
+impl Database {
+    /// Starts `sqlite3` on `path`, creating the tables if needed, and adds the session
+    /// recorded in `dir` under a new id.
+    pub fn open(path: &Path, dir: &str, started_at: u128) -> io::Result<Self> {
+        let mut child = Command::new("sqlite3")
+            .args(["-batch", "-bail"])
+            .arg(path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .spawn()
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::NotFound => {
+                    io::Error::new(e.kind(), "--sqlite needs the sqlite3 command-line shell")
+                }
+                _ => e,
+            })?;
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+
+        let id = new_uuid();
+        let session = quote(&id);
+        write!(stdin, "{}", SCHEMA)?;
+        writeln!(
+            stdin,
+            "INSERT INTO sessions (id, dir, started_at) VALUES ({}, {}, {});",
+            session,
+            quote(dir),
+            started_at
+        )?;
+        stdin.flush()?;
+
+        Ok(Database {
+            id,
+            session,
+            shell: Mutex::new(Shell {
+                child,
+                stdin: Some(stdin),
+                pending: String::new(),
+            }),
+        })
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn insert_reading(&self, timestamp: u128, data: &str) {
+        let statement = format!(
+            "INSERT INTO readings VALUES ({}, {}, {});\n",
+            self.session,
+            timestamp,
+            quote(data)
+        );
+        self.shell().push(&statement);
+    }
+
+    pub fn insert_label(&self, timestamp: u128, label: &str, extra: &[&str]) {
+        let extra = match extra {
+            [] => "NULL".to_string(),
+            _ => quote(&extra.join(";")),
+        };
+        let statement = format!(
+            "INSERT INTO labels VALUES ({}, {}, {}, {});\n",
+            self.session,
+            timestamp,
+            quote(label),
+            extra
+        );
+        self.shell().push(&statement);
+    }
+
+    /// Commits the rows inserted since the last commit in one transaction.
+    pub fn commit(&self) {
+        let mut shell = self.shell();
+        if shell.pending.is_empty() {
+            return;
+        }
+        let pending = std::mem::take(&mut shell.pending);
+        shell.write(&format!("BEGIN;\n{}COMMIT;\n", pending));
+    }
+
+    /// Commits what is left, records when the session ended and its metadata, and
+    /// waits for `sqlite3` to exit.
+    pub fn finish(&self, ended_at: u128, metadata: &str) {
+        self.commit();
+        let mut shell = self.shell();
+        shell.write(&format!(
+            "UPDATE sessions SET ended_at = {}, metadata = {} WHERE id = {};\n",
+            ended_at,
+            quote(metadata),
+            self.session
+        ));
+        let stopped = shell.stdin.take().is_none();
+
+        match shell.child.wait() {
+            Ok(status) if !status.success() && !stopped => {
+                eprintln!(
+                    "sqlite3 failed ({}), the database may be incomplete",
+                    status
+                )
+            }
+            Ok(_) => {}
+            Err(e) => eprintln!("Waiting for sqlite3 failed: {}", e),
+        }
+    }
+
+    fn shell(&self) -> MutexGuard<'_, Shell> {
+        self.shell.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Shell {
+    fn push(&mut self, statement: &str) {
+        if self.stdin.is_some() {
+            self.pending.push_str(statement);
+        }
+    }
+
+    /// Sends `sql` to `sqlite3`, stopping the mirror if that fails.
+    fn write(&mut self, sql: &str) {
+        let Some(stdin) = &mut self.stdin else {
+            return;
+        };
+        if let Err(e) = stdin.write_all(sql.as_bytes()).and_then(|_| stdin.flush()) {
+            eprintln!(
+                "sqlite3 stopped taking rows ({}), recording goes on without the database",
+                e
+            );
+            self.stdin = None;
+            self.pending.clear();
+        }
+    }
+}
+
+/// A random (version 4) UUID.
+fn new_uuid() -> String {
+    let mut bits = rand::random::<u128>();
+    // version 4 in the 13th hex digit, variant 10xx in the 17th
+    bits = (bits & !(0xf << 76)) | (0x4 << 76);
+    bits = (bits & !(0x3 << 62)) | (0x2 << 62);
+    let hex = format!("{:032x}", bits);
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+/// An SQL string literal.
+fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quote_doubles_single_quotes() {
+        assert_eq!(quote(""), "''");
+        assert_eq!(quote("plain"), "'plain'");
+        assert_eq!(quote("it's"), "'it''s'");
+        assert_eq!(quote("''"), "''''''");
+        assert_eq!(quote("a\"b;"), "'a\"b;'");
+    }
+
+    #[test]
+    fn uuid_is_random_version_4() {
+        for _ in 0..100 {
+            let id = new_uuid();
+            let groups: Vec<&str> = id.split('-').collect();
+            let lengths: Vec<usize> = groups.iter().map(|group| group.len()).collect();
+            assert_eq!(lengths, [8, 4, 4, 4, 12], "{id}");
+            assert!(id.chars().all(|c| c == '-' || c.is_ascii_hexdigit()));
+            assert!(groups[2].starts_with('4'), "version of {id}");
+            assert!(
+                matches!(groups[3].as_bytes()[0], b'8' | b'9' | b'a' | b'b'),
+                "variant of {id}"
+            );
+        }
+        assert_ne!(new_uuid(), new_uuid());
+    }
+}
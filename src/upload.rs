@@ -0,0 +1,192 @@
+//! `--upload`: POSTs a finished recording's archive to an HTTP endpoint. Plain
+//! HTTP/1.1 over a TcpStream; there is no TLS, so `https://` URLs are refused and the
+//! unencrypted upload has to be allowed with `--allow-insecure-upload`.
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+const ATTEMPTS: u32 = 4;
+const FIRST_BACKOFF: Duration = Duration::from_secs(1);
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Checks `url` before the session starts, so a bad one doesn't surface only after
+/// the recording is done.
+pub fn check_url(url: &str, allow_insecure: bool) -> io::Result<()> {
+    parse_url(url)?;
+    if !allow_insecure {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "--upload would send the recording unencrypted over plain HTTP to '{}'; \
+                 pass --allow-insecure-upload to do so anyway",
+                url
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Uploads `file`, retrying with exponential backoff. Returns the final HTTP status.
+pub fn post_file(url: &str, file: &Path) -> io::Result<u16> {
+    let (host, port, path) = parse_url(url)?;
+    let name = file
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or("recording.zip");
+
+    let mut backoff = FIRST_BACKOFF;
+    let mut attempt = 1;
+    loop {
+        let result = post(&host, port, &path, name, file);
+        match result {
+            Ok(status) if (200..300).contains(&status) => return Ok(status),
+            // client errors won't go away by retrying
+            Ok(status) if (400..500).contains(&status) => {
+                return Err(io::Error::other(format!("server answered {}", status)));
+            }
+            _ if attempt < ATTEMPTS => {
+                match &result {
+                    Ok(status) => eprintln!("Upload got {}, retrying in {:?}", status, backoff),
+                    Err(e) => eprintln!("Upload failed ({}), retrying in {:?}", e, backoff),
+                }
+                thread::sleep(backoff);
+                backoff *= 2;
+                attempt += 1;
+            }
+            Ok(status) => return Err(io::Error::other(format!("server answered {}", status))),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Streams the file at `body` as the request body, so the archive is never held in
+/// memory whole.
+fn post(host: &str, port: u16, path: &str, name: &str, body: &Path) -> io::Result<u16> {
+    let mut file = File::open(body)?;
+    let length = file.metadata()?.len();
+    let stream = TcpStream::connect((host, port))?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+
+    let mut writer = BufWriter::new(stream);
+    write!(
+        writer,
+        "POST {} HTTP/1.1\r\n\
+         Host: {}:{}\r\n\
+         Content-Type: application/zip\r\n\
+         Content-Length: {}\r\n\
+         X-Filename: {}\r\n\
+         Connection: close\r\n\r\n",
+        path, host, port, length, name
+    )?;
+    let sent = io::copy(&mut file, &mut writer)?;
+    if sent != length {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("'{}' changed while it was uploaded", body.display()),
+        ));
+    }
+    let stream = writer.into_inner().map_err(|e| e.into_error())?;
+
+    let mut status_line = String::new();
+    BufReader::new(stream).read_line(&mut status_line)?;
+    status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unexpected response '{}'", status_line.trim()),
+            )
+        })
+}
+
+/// Splits `http://host[:port][/path]` into its parts.
+fn parse_url(url: &str) -> io::Result<(String, u16, String)> {
+    let invalid = |problem: &str| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("'{}': {}", url, problem),
+        )
+    };
+
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| invalid("only http:// URLs are supported"))?;
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse().map_err(|_| invalid("bad port"))?),
+        None => (authority, 80),
+    };
+    if host.is_empty() {
+        return Err(invalid("missing host"));
+    }
+    Ok((host.to_string(), port, path.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    #[test]
+    fn urls() {
+        assert_eq!(
+            parse_url("http://example.org:8080/up").unwrap(),
+            ("example.org".to_string(), 8080, "/up".to_string())
+        );
+        assert_eq!(
+            parse_url("http://example.org").unwrap(),
+            ("example.org".to_string(), 80, "/".to_string())
+        );
+        assert!(parse_url("https://example.org/").is_err());
+        assert!(parse_url("http://:80/").is_err());
+        assert!(parse_url("http://example.org:x/").is_err());
+
+        assert!(check_url("http://example.org/", false).is_err());
+        assert!(check_url("http://example.org/", true).is_ok());
+    }
+
+    #[test]
+    fn file_is_streamed_as_the_body() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 4096];
+            // the body ends the request once the headers are in
+            while !request.ends_with(b"recording") {
+                let n = stream.read(&mut buf).unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            stream.write_all(b"HTTP/1.1 201 Created\r\n\r\n").unwrap();
+            String::from_utf8(request).unwrap()
+        });
+
+        let file =
+            std::env::temp_dir().join(format!("fidgetsense-upload-{}.zip", std::process::id()));
+        std::fs::write(&file, b"recording").unwrap();
+        let status = post_file(&format!("http://127.0.0.1:{}/up", port), &file);
+        std::fs::remove_file(&file).unwrap();
+        assert_eq!(status.unwrap(), 201);
+
+        let request = server.join().unwrap();
+        assert!(request.starts_with("POST /up HTTP/1.1\r\n"));
+        assert!(request.contains("Content-Length: 9\r\n"));
+        assert!(request.contains(&format!(
+            "X-Filename: fidgetsense-upload-{}.zip\r\n",
+            std::process::id()
+        )));
+        assert!(request.ends_with("\r\n\r\nrecording"));
+    }
+}
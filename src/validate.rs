@@ -0,0 +1,461 @@
+use crate::crc32::Crc32;
+use crate::{
+    Activity, END_MARKER, LABELS_HEADER, has_embedded_subject, is_label_annotation,
+    parse_label_row, parse_reading_row,
+};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+const MAX_REPORTED_ROWS: usize = 5;
+
+struct Check {
+    name: &'static str,
+    problems: Vec<String>,
+}
+
+impl Check {
+    fn new(name: &'static str) -> Self {
+        Check {
+            name,
+            problems: Vec::new(),
+        }
+    }
+
+    fn fail(&mut self, problem: String) {
+        self.problems.push(problem);
+    }
+
+    fn report(&self) {
+        if self.problems.is_empty() {
+            println!("  ok    {}", self.name);
+            return;
+        }
+
+        println!("  FAIL  {} ({} problems)", self.name, self.problems.len());
+        for problem in self.problems.iter().take(MAX_REPORTED_ROWS) {
+            println!("          {}", problem);
+        }
+        if self.problems.len() > MAX_REPORTED_ROWS {
+            println!("          ...");
+        }
+    }
+}
+
+/// One line of a recording's readings: `raw` as it was written, for the checksums, and
+/// `row` as a `timestamp;fields` CSV row whatever the --format.
+pub struct ReadingLine {
+    pub raw: String,
+    pub row: String,
+}
+
+pub fn run(dir: &Path) -> io::Result<()> {
+    let readings = read_readings(dir)?;
+    let embedded_subject = has_embedded_subject(dir);
+    let labels = fs::read_to_string(dir.join("labels.csv"))?;
+
+    let mut reading_times = Vec::new();
+    let mut readings_parse = Check::new("readings parse");
+    let mut checksums = Check::new("checksum lines match");
+    let mut crc = Crc32::new();
+    let mut since_checkpoint = 0;
+    for (i, line) in readings.iter().enumerate() {
+        let row = line.row.as_str();
+        // heartbeats and --inline-labels markers are not covered by the checksums
+        if row.starts_with("#heartbeat") || is_label_annotation(row) {
+            continue;
+        }
+        if let Some(checkpoint) = row.strip_prefix('#') {
+            if let Some(problem) = check_checkpoint(checkpoint, crc.value(), since_checkpoint) {
+                checksums.fail(format!("line {}: {}", i + 1, problem));
+            }
+            crc = Crc32::new();
+            since_checkpoint = 0;
+            continue;
+        }
+        crc.update(line.raw.as_bytes());
+        since_checkpoint += 1;
+        match parse_reading_row(row, embedded_subject) {
+            Some((t, _)) => reading_times.push(t),
+            None => readings_parse.fail(format!("line {}: '{}'", i + 1, row)),
+        }
+    }
+
+    let mut label_rows = Vec::new();
+    let mut labels_parse = Check::new("labels parse");
+    for (i, row) in labels.lines().enumerate() {
+        if is_label_annotation(row) || (i == 0 && row == LABELS_HEADER) {
+            continue;
+        }
+        match parse_label_row(row) {
+            Some(label) => label_rows.push(label),
+            None => labels_parse.fail(format!("line {}: '{}'", i + 1, row)),
+        }
+    }
+
+    let mut ended_cleanly = Check::new("session ended cleanly");
+    let end_marker = format!("#{}", END_MARKER);
+    if labels.lines().last().and_then(|row| row.split(';').nth(1)) != Some(end_marker.as_str()) {
+        ended_cleanly.fail(format!("labels.csv does not end with a {} row", end_marker));
+    }
+
+    let mut readings_monotonic = Check::new("reading timestamps increase");
+    for pair in reading_times.windows(2) {
+        if pair[1] < pair[0] {
+            readings_monotonic.fail(format!("{} follows {}", pair[1], pair[0]));
+        }
+    }
+
+    let mut labels_monotonic = Check::new("label timeline is monotonic");
+    for pair in label_rows.windows(2) {
+        if pair[1].0 < pair[0].0 {
+            labels_monotonic.fail(format!("{} follows {}", pair[1].0, pair[0].0));
+        }
+    }
+
+    let mut activities_have_samples = Check::new("every activity has samples");
+    for (i, (start, activity)) in label_rows.iter().enumerate() {
+        if matches!(activity, Activity::OTHER | Activity::PREPARE) {
+            continue;
+        }
+        let end = label_rows.get(i + 1).map_or(u128::MAX, |(t, _)| *t);
+        let samples = reading_times
+            .iter()
+            .filter(|&&t| t >= *start && t < end)
+            .count();
+        if samples == 0 {
+            activities_have_samples.fail(format!("{:?} starting at {}", activity, start));
+        }
+    }
+
+    let checks = [
+        readings_parse,
+        checksums,
+        labels_parse,
+        ended_cleanly,
+        readings_monotonic,
+        labels_monotonic,
+        activities_have_samples,
+    ];
+
+    println!("{}", dir.display());
+    println!(
+        "{} readings, {} labels",
+        reading_times.len(),
+        label_rows.len()
+    );
+    for check in &checks {
+        check.report();
+    }
+
+    if checks.iter().all(|check| check.problems.is_empty()) {
+        println!("PASS");
+        Ok(())
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("'{}' failed validation", dir.display()),
+        ))
+    }
+}
+
+/// Compares a `crc32=<hex>;samples=<n>` checkpoint with the rows before it.
+fn check_checkpoint(checkpoint: &str, crc: u32, samples: u64) -> Option<String> {
+    let mut expected_crc = None;
+    let mut expected_samples = None;
+    for field in checkpoint.split(';') {
+        match field.split_once('=') {
+            Some(("crc32", value)) => expected_crc = u32::from_str_radix(value, 16).ok(),
+            Some(("samples", value)) => expected_samples = value.parse::<u64>().ok(),
+            _ => {}
+        }
+    }
+
+    match (expected_crc, expected_samples) {
+        (Some(expected_crc), Some(expected_samples)) => {
+            if expected_samples != samples {
+                Some(format!(
+                    "{} rows since the previous checkpoint, expected {}",
+                    samples, expected_samples
+                ))
+            } else if expected_crc != crc {
+                Some(format!("crc32 {:08x}, expected {:08x}", crc, expected_crc))
+            } else {
+                None
+            }
+        }
+        _ => Some(format!("unrecognized comment '#{}'", checkpoint)),
+    }
+}
+
+/// Reads readings.<ext>, or the per-window files of a --split-by-activity recording
+/// (or the chunks of a --max-file-bytes one) in order, decoding each by its extension.
+pub fn read_readings(dir: &Path) -> io::Result<Vec<ReadingLine>> {
+    let mut files = Vec::new();
+    let mut windows = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let Some(stem) = path.file_stem().and_then(|stem| stem.to_str()) else {
+            continue;
+        };
+        if stem == "readings" {
+            files.push(path);
+            continue;
+        }
+        let window = Some(stem)
+            .filter(|stem| stem.starts_with("readings_") || stem.starts_with("readings."))
+            .and_then(|stem| stem.rsplit(['_', '.']).next())
+            .and_then(|window| window.parse::<usize>().ok());
+        if let Some(window) = window {
+            windows.push((window, path));
+        }
+    }
+    if files.is_empty() {
+        windows.sort();
+        files = windows.into_iter().map(|(_, path)| path).collect();
+    }
+    if files.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no readings found in '{}'", dir.display()),
+        ));
+    }
+
+    let mut lines = Vec::new();
+    for path in files {
+        read_readings_file(&path, &mut lines)?;
+    }
+    Ok(lines)
+}
+
+fn read_readings_file(path: &Path, lines: &mut Vec<ReadingLine>) -> io::Result<()> {
+    let extension = path.extension().and_then(|extension| extension.to_str());
+    match extension {
+        Some("csv") => {
+            for raw in fs::read_to_string(path)?.split_inclusive('\n') {
+                let row = raw.trim_end_matches(['\r', '\n']).to_string();
+                lines.push(ReadingLine {
+                    raw: raw.to_string(),
+                    row,
+                });
+            }
+        }
+        Some("ndjson") => {
+            for raw in fs::read_to_string(path)?.split_inclusive('\n') {
+                let line = raw.trim_end_matches(['\r', '\n']);
+                // heartbeats and checksum lines are written as in a CSV file; an object
+                // that doesn't decode is kept as is and fails to parse as a reading
+                let row = match line.starts_with('{') {
+                    true => ndjson_row(line).unwrap_or_else(|| line.to_string()),
+                    false => line.to_string(),
+                };
+                lines.push(ReadingLine {
+                    raw: raw.to_string(),
+                    row,
+                });
+            }
+        }
+        #[cfg(feature = "arrow")]
+        Some("arrow") => {
+            let rows = crate::arrow::read_rows(&fs::read(path)?)
+                .map_err(|e| io::Error::new(e.kind(), format!("'{}': {}", path.display(), e)))?;
+            for (timestamp, fields) in rows {
+                let fields: Vec<String> = fields.iter().map(f64::to_string).collect();
+                let row = format!("{};{}", timestamp, fields.join(","));
+                lines.push(ReadingLine {
+                    raw: format!("{}\n", row),
+                    row,
+                });
+            }
+        }
+        #[cfg(not(feature = "arrow"))]
+        Some("arrow") => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "'{}' is an Arrow file, which needs a build with the arrow feature",
+                    path.display()
+                ),
+            ));
+        }
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("'{}' is not in a known readings format", path.display()),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Turns a --format ndjson reading, `{"t":..,"fields":[..]}` or `{"t":..,"raw":".."}`,
+/// back into the `timestamp;line` row readings.csv would hold. Null fields, written for
+/// values that are not finite, come back as NaN.
+fn ndjson_row(object: &str) -> Option<String> {
+    let rest = object.strip_prefix("{\"t\":")?.strip_suffix('}')?;
+    let (timestamp, rest) = rest.split_once(',')?;
+    let timestamp: u128 = timestamp.parse().ok()?;
+    if let Some(fields) = rest.strip_prefix("\"fields\":[") {
+        let fields: Vec<&str> = fields
+            .strip_suffix(']')?
+            .split(',')
+            .map(|field| if field == "null" { "NaN" } else { field })
+            .collect();
+        return Some(format!("{};{}", timestamp, fields.join(",")));
+    }
+    let raw = rest.strip_prefix("\"raw\":")?;
+    Some(format!("{};{}", timestamp, json_string(raw)?))
+}
+
+/// Unescapes a whole JSON string literal.
+fn json_string(literal: &str) -> Option<String> {
+    let mut chars = literal.strip_prefix('"')?.strip_suffix('"')?.chars();
+    let mut value = String::new();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            value.push(c);
+            continue;
+        }
+        match chars.next()? {
+            'n' => value.push('\n'),
+            'r' => value.push('\r'),
+            't' => value.push('\t'),
+            'u' => {
+                let hex: String = chars.by_ref().take(4).collect();
+                value.push(char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?);
+            }
+            c => value.push(c),
+        }
+    }
+    Some(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checkpoint_against_known_crc() {
+        let mut crc = Crc32::new();
+        crc.update(b"123456789");
+        let crc = crc.value();
+        assert_eq!(check_checkpoint("crc32=cbf43926;samples=1", crc, 1), None);
+        assert_eq!(
+            check_checkpoint("crc32=cbf43926;samples=2", crc, 1),
+            Some("1 rows since the previous checkpoint, expected 2".to_string())
+        );
+        assert_eq!(
+            check_checkpoint("crc32=00000000;samples=1", crc, 1),
+            Some("crc32 cbf43926, expected 00000000".to_string())
+        );
+        assert_eq!(
+            check_checkpoint("note", crc, 1),
+            Some("unrecognized comment '#note'".to_string())
+        );
+    }
+
+    /// The rows `read_readings` finds in a directory holding `files`.
+    fn rows_of(test: &str, files: &[(&str, &[u8])]) -> io::Result<Vec<String>> {
+        let dir = std::env::temp_dir().join(format!(
+            "fidgetsense-validate-{}-{}",
+            test,
+            std::process::id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        for (name, contents) in files {
+            fs::write(dir.join(name), contents).unwrap();
+        }
+        let readings = read_readings(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+        Ok(readings?.into_iter().map(|line| line.row).collect())
+    }
+
+    #[test]
+    fn split_readings_are_read_in_window_order() {
+        let rows = rows_of(
+            "split",
+            &[
+                ("readings_F_010.csv", b"3;1\n"),
+                ("readings_N_002.csv", b"2;1\n"),
+                ("readings_F_001.csv", b"1;1\n"),
+                ("readings_F_011.csv.partial", b"4;1\n"),
+            ],
+        );
+        assert_eq!(rows.unwrap(), ["1;1", "2;1", "3;1"]);
+    }
+
+    #[test]
+    fn ndjson_rows_read_like_csv() {
+        let ndjson = concat!(
+            "{\"t\":5,\"fields\":[1.5,null,2]}\n",
+            "{\"t\":6,\"raw\":\"ok \\\"x\\\"\\t\\u0001\"}\n",
+            "#heartbeat;7\n",
+            "{\"t\":8,\"what\":1}\n",
+        );
+        let rows = rows_of("ndjson", &[("readings.ndjson", ndjson.as_bytes())]).unwrap();
+        assert_eq!(
+            rows,
+            [
+                "5;1.5,NaN,2",
+                "6;ok \"x\"\t\u{1}",
+                "#heartbeat;7",
+                "{\"t\":8,\"what\":1}"
+            ]
+        );
+        assert!(parse_reading_row(&rows[0], false).is_some());
+        assert!(parse_reading_row(&rows[3], false).is_none());
+    }
+
+    #[test]
+    fn ndjson_chunks_keep_the_lines_for_the_checksums() {
+        let first = "{\"t\":1,\"fields\":[1]}\n";
+        let mut crc = Crc32::new();
+        crc.update(first.as_bytes());
+        let chunk = format!("{}#crc32={:08x};samples=1\n", first, crc.value());
+        let dir = std::env::temp_dir().join(format!("fidgetsense-chunks-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("readings.000.ndjson"), &chunk).unwrap();
+        fs::write(
+            dir.join("readings.001.ndjson"),
+            "{\"t\":2,\"fields\":[2]}\n",
+        )
+        .unwrap();
+        let readings = read_readings(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let readings = readings.unwrap();
+        let rows: Vec<&str> = readings.iter().map(|line| line.row.as_str()).collect();
+        assert_eq!(rows, ["1;1", &chunk[first.len()..chunk.len() - 1], "2;2"]);
+        assert_eq!(readings[0].raw, first);
+        let checkpoint = rows[1].strip_prefix('#').unwrap();
+        assert_eq!(check_checkpoint(checkpoint, crc.value(), 1), None);
+    }
+
+    #[cfg(feature = "arrow")]
+    #[test]
+    fn arrow_rows_read_like_csv() {
+        let mut arrow = crate::arrow::ArrowFile::new(None);
+        let mut file = Vec::new();
+        arrow.push(10, &[1.5, -2.0]);
+        arrow.push(20, &[f64::NAN, 3.0]);
+        arrow.finish(&mut file).unwrap();
+        let rows = rows_of("arrow", &[("readings.arrow", &file)]).unwrap();
+        assert_eq!(rows, ["10;1.5,-2", "20;NaN,3"]);
+        assert!(parse_reading_row(&rows[1], false).is_some());
+    }
+
+    #[cfg(not(feature = "arrow"))]
+    #[test]
+    fn arrow_needs_the_feature() {
+        let error = rows_of("arrow", &[("readings.arrow", b"ARROW1")]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn unknown_formats_are_rejected() {
+        let error = rows_of("unknown", &[("readings.txt", b"1;1\n")]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        let error = rows_of("none", &[("labels.csv", b"")]).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+    }
+}
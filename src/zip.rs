@@ -0,0 +1,242 @@
+//! Minimal zip writer for archiving a recording directory. Entries are stored
+//! uncompressed, which keeps this small and readable by any unzip tool.
+
+use crate::crc32::Crc32;
+use crate::local_time;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const LOCAL_HEADER_SIG: u32 = 0x0403_4b50;
+const CENTRAL_HEADER_SIG: u32 = 0x0201_4b50;
+const END_OF_CENTRAL_DIR_SIG: u32 = 0x0605_4b50;
+const VERSION: u16 = 20;
+/// Unix host in the high byte of "version made by", so the file modes are kept.
+const VERSION_MADE_BY: u16 = (3 << 8) | VERSION;
+/// Bit 11: entry names are UTF-8.
+const FLAGS: u16 = 0x0800;
+const METHOD_STORED: u16 = 0;
+
+struct Entry {
+    name: String,
+    crc: u32,
+    size: u32,
+    mode: u32,
+    time: u16,
+    date: u16,
+    offset: u32,
+}
+
+/// Writes every file under `dir` into the zip at `out`, with paths prefixed by the
+/// directory's own name so it unpacks into a folder.
+pub fn archive_dir(dir: &Path, out: &Path) -> io::Result<()> {
+    let root = dir
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "directory has no name"))?;
+
+    let mut files = Vec::new();
+    collect_files(dir, &mut files)?;
+    files.sort();
+
+    let mut writer = BufWriter::new(File::create(out)?);
+    let mut offset: u64 = 0;
+    let mut entries = Vec::new();
+    for path in files {
+        let relative = path.strip_prefix(dir).unwrap_or(&path);
+        let name = format!("{}/{}", root, relative.to_string_lossy());
+        let metadata = fs::metadata(&path)?;
+
+        // without zip64, sizes and offsets have to fit in 32 bits
+        let size = u32::try_from(metadata.len()).map_err(|_| too_large(&path))?;
+        let (time, date) = dos_date_time(metadata.modified()?);
+        let entry = Entry {
+            name,
+            crc: file_crc(&path, size)?,
+            size,
+            mode: file_mode(&metadata),
+            time,
+            date,
+            offset: u32::try_from(offset).map_err(|_| too_large(&path))?,
+        };
+
+        let mut header = Vec::with_capacity(30 + entry.name.len());
+        put_u32(&mut header, LOCAL_HEADER_SIG);
+        put_u16(&mut header, VERSION);
+        put_u16(&mut header, FLAGS);
+        put_u16(&mut header, METHOD_STORED);
+        put_u16(&mut header, entry.time);
+        put_u16(&mut header, entry.date);
+        put_u32(&mut header, entry.crc);
+        put_u32(&mut header, entry.size);
+        put_u32(&mut header, entry.size);
+        put_u16(&mut header, entry.name.len() as u16);
+        put_u16(&mut header, 0);
+        header.extend_from_slice(entry.name.as_bytes());
+
+        writer.write_all(&header)?;
+        let copied = io::copy(&mut File::open(&path)?.take(u64::from(size)), &mut writer)?;
+        if copied != u64::from(size) {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("'{}' shrank while it was zipped", path.display()),
+            ));
+        }
+        offset += header.len() as u64 + copied;
+        entries.push(entry);
+    }
+
+    let central_offset = u32::try_from(offset).map_err(|_| too_large(out))?;
+    let mut central = Vec::new();
+    for entry in &entries {
+        put_u32(&mut central, CENTRAL_HEADER_SIG);
+        put_u16(&mut central, VERSION_MADE_BY);
+        put_u16(&mut central, VERSION);
+        put_u16(&mut central, FLAGS);
+        put_u16(&mut central, METHOD_STORED);
+        put_u16(&mut central, entry.time);
+        put_u16(&mut central, entry.date);
+        put_u32(&mut central, entry.crc);
+        put_u32(&mut central, entry.size);
+        put_u32(&mut central, entry.size);
+        put_u16(&mut central, entry.name.len() as u16);
+        put_u16(&mut central, 0); // extra field length
+        put_u16(&mut central, 0); // comment length
+        put_u16(&mut central, 0); // disk number
+        put_u16(&mut central, 0); // internal attributes
+        put_u32(&mut central, entry.mode << 16);
+        put_u32(&mut central, entry.offset);
+        central.extend_from_slice(entry.name.as_bytes());
+    }
+
+    let count = u16::try_from(entries.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "too many files to zip"))?;
+    let central_size = u32::try_from(central.len()).map_err(|_| too_large(out))?;
+    put_u32(&mut central, END_OF_CENTRAL_DIR_SIG);
+    put_u16(&mut central, 0);
+    put_u16(&mut central, 0);
+    put_u16(&mut central, count);
+    put_u16(&mut central, count);
+    put_u32(&mut central, central_size);
+    put_u32(&mut central, central_offset);
+    put_u16(&mut central, 0);
+
+    writer.write_all(&central)?;
+    writer.into_inner().map_err(|e| e.into_error())?.sync_all()
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_files(&path, files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// CRC-32 of the first `size` bytes of the file at `path`, read in chunks.
+fn file_crc(path: &Path, size: u32) -> io::Result<u32> {
+    let mut file = File::open(path)?.take(u64::from(size));
+    let mut crc = Crc32::new();
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            return Ok(crc.value());
+        }
+        crc.update(&buf[..n]);
+    }
+}
+
+fn too_large(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("'{}' is too large for a zip archive", path.display()),
+    )
+}
+
+#[cfg(unix)]
+fn file_mode(metadata: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode()
+}
+
+#[cfg(not(unix))]
+fn file_mode(_metadata: &fs::Metadata) -> u32 {
+    0o100644
+}
+
+/// MS-DOS time and date fields in local time, as zip expects.
+fn dos_date_time(time: SystemTime) -> (u16, u16) {
+    let tm = local_time(time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()) as i64);
+
+    // DOS dates start in 1980
+    let year = (tm.year - 1980).clamp(0, 127) as u16;
+    let time = ((tm.hour as u16) << 11) | ((tm.minute as u16) << 5) | (tm.second as u16 / 2);
+    let date = (year << 9) | ((tm.month as u16) << 5) | tm.day as u16;
+    (time, date)
+}
+
+fn put_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u16_at(bytes: &[u8], at: usize) -> u16 {
+        u16::from_le_bytes([bytes[at], bytes[at + 1]])
+    }
+
+    fn u32_at(bytes: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+    }
+
+    #[test]
+    fn stored_entries_and_central_directory() {
+        let base = std::env::temp_dir().join(format!("fidgetsense-zip-{}", std::process::id()));
+        let dir = base.join("rec");
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("a.txt"), "123456789").unwrap();
+        fs::write(dir.join("sub").join("b.txt"), "").unwrap();
+        let out = base.join("rec.zip");
+        archive_dir(&dir, &out).unwrap();
+        let zip = fs::read(&out).unwrap();
+        fs::remove_dir_all(&base).unwrap();
+
+        // first local header: rec/a.txt, stored, with the CRC-32 check value
+        assert_eq!(u32_at(&zip, 0), LOCAL_HEADER_SIG);
+        assert_eq!(u16_at(&zip, 8), METHOD_STORED);
+        assert_eq!(u32_at(&zip, 14), 0xcbf4_3926);
+        assert_eq!((u32_at(&zip, 18), u32_at(&zip, 22)), (9, 9));
+        assert_eq!(u16_at(&zip, 26), 9);
+        assert_eq!(&zip[30..39], b"rec/a.txt");
+        assert_eq!(&zip[39..48], b"123456789");
+
+        // second local header right after the data
+        assert_eq!(u32_at(&zip, 48), LOCAL_HEADER_SIG);
+        assert_eq!(&zip[78..91], b"rec/sub/b.txt");
+
+        let end = zip.len() - 22;
+        assert_eq!(u32_at(&zip, end), END_OF_CENTRAL_DIR_SIG);
+        assert_eq!((u16_at(&zip, end + 8), u16_at(&zip, end + 10)), (2, 2));
+        let central_size = u32_at(&zip, end + 12) as usize;
+        let central_offset = u32_at(&zip, end + 16) as usize;
+        assert_eq!(central_offset, 91);
+        assert_eq!(central_offset + central_size, end);
+        assert_eq!(u32_at(&zip, central_offset), CENTRAL_HEADER_SIG);
+        assert_eq!(u32_at(&zip, central_offset + 42), 0);
+        let second = central_offset + 46 + 9;
+        assert_eq!(u32_at(&zip, second), CENTRAL_HEADER_SIG);
+        assert_eq!(u32_at(&zip, second + 42), 48);
+    }
+}